@@ -202,13 +202,7 @@ impl Internal {
             .with_client(WasiHttpClient)
             .with_key(&parsed_config.api_key);
 
-        log(
-            "info",
-            &format!(
-                "Initialized Polygon client with WASI HTTP using API key: {}...",
-                &parsed_config.api_key[..3.min(parsed_config.api_key.len())]
-            ),
-        );
+        log("info", "Initialized Polygon client with WASI HTTP");
         Self(client)
     }
 
@@ -0,0 +1,267 @@
+//! An append-only record of every host call an extension makes.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::data::Id;
+
+/// How many records are kept in memory by default
+const DEFAULT_RETENTION: usize = 10_000;
+
+/// Something an extension did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// An outbound HTTP request. `bytes` counts the response body the extension read.
+    Http {
+        method: String,
+        url: String,
+        status: Option<u16>,
+        bytes: u64,
+        error: Option<String>,
+    },
+    /// A call to the `log` import
+    Log { level: String, message: String },
+    /// A call to the `secret` import
+    Secret { name: String, granted: bool },
+    /// A host directory opened for the extension
+    Filesystem { path: PathBuf, granted: bool },
+    /// A file or directory the extension opened, by the path it asked for relative to the directory it opened it
+    /// in, and the mode it asked for, e.g. `read,write,create`
+    Open {
+        path: String,
+        mode: String,
+        error: Option<String>,
+    },
+    /// A command received from the host, as sent to the extension
+    Command { command: String },
+    /// A call to the `publish` import
+    Publish { topic: String, granted: bool },
+    /// A call to the `call-tool` import
+    Tool {
+        target: Id,
+        tool_id: String,
+        granted: bool,
+        error: Option<String>,
+    },
+}
+
+/// One line of the audit trail
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub extension_id: Id,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Filters for [`Audit::query`]
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    extension_id: Option<Id>,
+    kind: Option<String>,
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only records of this extension
+    pub fn extension(mut self, extension_id: impl Into<Id>) -> Self {
+        self.extension_id = Some(extension_id.into());
+        self
+    }
+
+    /// Only events of this kind, e.g. `"http"` or `"secret"`
+    pub fn kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = Some(kind.into());
+        self
+    }
+
+    /// Only records made at or after `time`
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(millis(time));
+        self
+    }
+
+    /// At most the `limit` most recent matching records
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        self.extension_id.as_ref().is_none_or(|id| *id == record.extension_id)
+            && self.kind.as_deref().is_none_or(|kind| kind == record.event.kind())
+            && self.since.is_none_or(|since| record.timestamp >= since)
+    }
+}
+
+/// A shared audit trail.
+///
+/// The most recent records are kept in memory for [`query`](Self::query); with a file sink every record is also
+/// appended to a JSON Lines file.
+#[derive(Debug, Clone)]
+pub struct Audit {
+    shared: Arc<Mutex<Trail>>,
+}
+
+#[derive(Debug)]
+struct Trail {
+    records: VecDeque<Record>,
+    retention: usize,
+    sink: Option<(PathBuf, File)>,
+}
+
+impl Audit {
+    /// Create an in-memory audit trail
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Trail {
+                records: VecDeque::new(),
+                retention: DEFAULT_RETENTION,
+                sink: None,
+            })),
+        }
+    }
+
+    /// Create an audit trail that also appends every record to the JSON Lines file at `path`
+    pub fn with_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let audit = Self::new();
+        audit.shared.lock().unwrap().sink = Some((path, file));
+        Ok(audit)
+    }
+
+    /// Keep at most `retention` records in memory
+    pub fn with_retention(self, retention: usize) -> Self {
+        self.shared.lock().unwrap().retention = retention;
+        self
+    }
+
+    /// Append a record for `extension_id`
+    pub fn record(&self, extension_id: &str, event: Event) {
+        let record = Record {
+            timestamp: millis(SystemTime::now()),
+            extension_id: extension_id.to_string(),
+            event,
+        };
+
+        let mut trail = self.shared.lock().unwrap();
+
+        if let Some((path, file)) = &mut trail.sink {
+            let result = serde_json::to_string(&record)
+                .map_err(std::io::Error::other)
+                .and_then(|line| writeln!(file, "{line}"));
+            if let Err(e) = result {
+                eprintln!("Failed to write audit record to {}: {}", path.display(), e);
+            }
+        }
+
+        trail.records.push_back(record);
+        while trail.records.len() > trail.retention {
+            trail.records.pop_front();
+        }
+    }
+
+    /// Records in memory that match `query`, oldest first
+    pub fn query(&self, query: &Query) -> Vec<Record> {
+        let trail = self.shared.lock().unwrap();
+        let mut records: Vec<Record> = trail.records.iter().filter(|r| query.matches(r)).cloned().collect();
+
+        if let Some(limit) = query.limit {
+            records.drain(..records.len().saturating_sub(limit));
+        }
+        records
+    }
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Event {
+    /// The `kind` tag of the event as it appears in the JSON Lines output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Http { .. } => "http",
+            Event::Log { .. } => "log",
+            Event::Secret { .. } => "secret",
+            Event::Filesystem { .. } => "filesystem",
+            Event::Open { .. } => "open",
+            Event::Command { .. } => "command",
+            Event::Tool { .. } => "tool",
+            Event::Publish { .. } => "publish",
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_and_file_sink() {
+        let path = std::env::temp_dir().join(format!("emporium-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = Audit::with_file(&path).unwrap();
+
+        audit.record(
+            "polygon",
+            Event::Log {
+                level: "info".to_string(),
+                message: "hello".to_string(),
+            },
+        );
+        audit.record(
+            "polygon",
+            Event::Secret {
+                name: "api_key".to_string(),
+                granted: true,
+            },
+        );
+        audit.record(
+            "kv",
+            Event::Command {
+                command: "{}".to_string(),
+            },
+        );
+
+        assert_eq!(audit.query(&Query::new()).len(), 3);
+        assert_eq!(audit.query(&Query::new().extension("polygon")).len(), 2);
+        assert_eq!(audit.query(&Query::new().kind("secret")).len(), 1);
+        assert_eq!(audit.query(&Query::new().limit(1))[0].extension_id, "kv");
+
+        let lines: Vec<Record> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, audit.query(&Query::new()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
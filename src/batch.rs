@@ -0,0 +1,111 @@
+//! Execute many tools at once, see [`Registry::execute_batch`](crate::Registry::execute_batch).
+use std::future::Future;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, Response};
+
+/// How many calls of a batch run at once unless it says otherwise
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Tool calls to make together, whose results come back in the same order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Batch {
+    pub calls: Vec<BatchCall>,
+    /// At most this many calls in flight at once, or [`DEFAULT_CONCURRENCY`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+}
+
+/// One call of a [`Batch`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchCall {
+    /// The qualified tool id, e.g. `polygon.call_endpoint`
+    pub tool_id: String,
+    pub params: Value,
+}
+
+impl Batch {
+    /// An empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a call of the qualified `tool_id` with `params`
+    pub fn call(mut self, tool_id: impl Into<String>, params: Value) -> Self {
+        self.calls.push(BatchCall {
+            tool_id: tool_id.into(),
+            params,
+        });
+        self
+    }
+
+    /// Run at most `concurrency` calls at once
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+}
+
+/// Run every call of `batch` with `execute`, keeping the results in order
+pub(crate) async fn run<F, Fut>(batch: Batch, execute: F) -> Vec<Result<Response, Error>>
+where
+    F: Fn(BatchCall) -> Fut,
+    Fut: Future<Output = Result<Response, Error>>,
+{
+    let concurrency = batch.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+
+    futures::stream::iter(batch.calls)
+        .map(execute)
+        .buffered(concurrency)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_batch_keeps_order_within_limit() {
+        let batch = (0..20).fold(Batch::new().concurrency(3), |batch, i| {
+            batch.call(if i == 7 { "missing.tool" } else { "polygon.quote" }, json!(i))
+        });
+
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let execute = |call: BatchCall| {
+            let (running, most) = (&running, &most);
+            async move {
+                most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                // Later calls finish first
+                let i = call.params.as_u64().unwrap();
+                tokio::time::sleep(Duration::from_millis(20 - i)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                match call.tool_id.as_str() {
+                    "polygon.quote" => Ok(Response::ToolResult {
+                        tool_id: call.tool_id,
+                        result: call.params,
+                    }),
+                    tool_id => Err(Error::RegistryNotFound(format!("Tool {} not found", tool_id))),
+                }
+            }
+        };
+
+        let results = run(batch, execute).await;
+        assert_eq!(results.len(), 20);
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(Response::ToolResult { result, .. }) => assert_eq!(result, json!(i)),
+                Err(Error::RegistryNotFound(_)) => assert_eq!(i, 7),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Send one command to many extensions at once.
+use std::time::Duration;
+
+use crate::data::{Command, Id};
+
+/// A command to send to every extension that matches, see [`Registry::broadcast`](crate::Registry::broadcast)
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub(crate) command: Command,
+    pattern: Option<String>,
+    capability: Option<String>,
+    pub(crate) timeout: Duration,
+}
+
+impl Broadcast {
+    /// Send `command` to every registered extension, waiting up to 10 seconds for each
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            pattern: None,
+            capability: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Only extensions whose id matches `pattern`, where `*` matches any run of characters and `?` any one
+    pub fn matching(mut self, pattern: impl Into<String>) -> Self {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    /// Only extensions whose manifest enables `capability`
+    pub fn capability(mut self, capability: impl Into<String>) -> Self {
+        self.capability = Some(capability.into());
+        self
+    }
+
+    /// How long to wait for each extension to answer
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the extension `id`, with the given manifest capabilities, is a recipient
+    pub(crate) fn selects(&self, id: &Id, capabilities: &[String]) -> bool {
+        self.pattern.as_deref().is_none_or(|pattern| glob(pattern, id))
+            && self
+                .capability
+                .as_ref()
+                .is_none_or(|capability| capabilities.contains(capability))
+    }
+}
+
+/// Match `text` against a pattern of literal characters, `*` and `?`
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has swallowed so far
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    p = star + 1;
+                    t = swallowed + 1;
+                    backtrack = Some((star, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selects() {
+        assert!(glob("*", "polygon"));
+        assert!(glob("xt-*", "xt-polygon"));
+        assert!(glob("*gon", "polygon"));
+        assert!(glob("p?ly*n", "polygon"));
+        assert!(!glob("xt-*", "polygon"));
+        assert!(!glob("poly", "polygon"));
+
+        let network = vec!["network".to_string()];
+        let broadcast = Broadcast::new(Command::ListTools).matching("p*").capability("network");
+        assert!(broadcast.selects(&"polygon".to_string(), &network));
+        assert!(!broadcast.selects(&"polygon".to_string(), &[]));
+        assert!(!broadcast.selects(&"alphavantage".to_string(), &network));
+    }
+}
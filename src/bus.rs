@@ -0,0 +1,26 @@
+//! Topics that extensions and the host publish events on.
+//!
+//! Topics are free-form strings such as `quotes.AAPL`. Subscriptions are patterns over topics, where `*` matches
+//! any run of characters and `?` any one, so `quotes.*` receives every quote.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::broadcast::glob;
+use crate::data::Id;
+
+/// The capability an extension's manifest must enable to publish or subscribe
+pub const CAPABILITY: &str = "events";
+
+/// An event published on the bus
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub topic: String,
+    /// The extension that published it, or `None` for the host
+    pub source: Option<Id>,
+    pub payload: Value,
+}
+
+/// Whether a subscription `pattern` covers `topic`
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    glob(pattern, topic)
+}
@@ -0,0 +1,164 @@
+//! An opt-in cache for tool results, see [`Registry::with_tool_cache`](crate::Registry::with_tool_cache).
+//!
+//! Only tools whose [`ToolInfo`](crate::data::ToolInfo) declares them `cacheable` are cached, keyed by extension, its
+//! version, tool and parameters. Parameters are compared as JSON values, so the order of object fields does not
+//! matter. Only successful results are stored, for the tool's `cache_ttl` or else the cache's own TTL. An extension's
+//! results are dropped when it is reloaded or unregistered.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::data::Id;
+use crate::ttl::Store;
+pub use crate::ttl::{Backend, Stats};
+
+/// Stores tool results, keyed per extension
+#[derive(Debug, Clone)]
+pub struct Cache {
+    store: Store<Value>,
+    pub(crate) ttl: Option<Duration>,
+}
+
+impl Cache {
+    /// Create a cache that keeps results in memory
+    pub fn memory() -> Self {
+        Self::new(Backend::Memory)
+    }
+
+    /// Create a cache that keeps results on disk under `dir`
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self::new(Backend::Disk(dir.into()))
+    }
+
+    /// Create a cache with the given backend
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            store: Store::new(backend),
+            ttl: None,
+        }
+    }
+
+    /// Cache results of tools that do not declare a TTL for `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Counters for a single extension
+    pub fn stats(&self, extension_id: &str) -> Stats {
+        self.store.stats(extension_id)
+    }
+
+    /// Counters for every extension that used the cache
+    pub fn all_stats(&self) -> HashMap<Id, Stats> {
+        self.store.all_stats()
+    }
+
+    /// Drop every stored result
+    pub fn clear(&self) -> std::io::Result<()> {
+        self.store.clear()
+    }
+
+    /// Drop every stored result of an extension
+    pub fn purge(&self, extension_id: &str) -> std::io::Result<()> {
+        self.store.purge(extension_id)
+    }
+
+    /// The key of a call to `tool_id` of version `version` of `extension_id` with `params`
+    pub(crate) fn key(extension_id: &str, version: &str, tool_id: &str, params: &Value) -> String {
+        let mut canonical = String::new();
+        canonicalize(params, &mut canonical);
+        let digest = Sha256::digest(format!("{extension_id}\n{version}\n{tool_id}\n{canonical}").as_bytes());
+        hex::encode(digest)
+    }
+
+    /// A stored result that has not expired, counting the lookup as a hit or a miss
+    pub(crate) async fn get(&self, extension_id: &str, key: &str) -> Option<Value> {
+        self.store.get(extension_id, key).await
+    }
+
+    /// Store a result for `ttl`
+    pub(crate) async fn put(&self, extension_id: &str, key: String, result: Value, ttl: Duration) {
+        self.store.put(extension_id, key, result, ttl).await
+    }
+}
+
+/// Write `value` as JSON with object fields sorted by name
+fn canonicalize(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(fields) => {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_by_key(|(name, _)| *name);
+
+            out.push('{');
+            for (i, (name, value)) in fields.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(name.clone()).to_string());
+                out.push(':');
+                canonicalize(value, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonicalize(item, out);
+            }
+            out.push(']');
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_results_are_keyed_by_params() {
+        let a = Cache::key(
+            "polygon",
+            "0.1.0",
+            "quote",
+            &json!({ "ticker": "AAPL", "range": { "from": 1, "to": 2 } }),
+        );
+        let b = Cache::key(
+            "polygon",
+            "0.1.0",
+            "quote",
+            &json!({ "range": { "to": 2, "from": 1 }, "ticker": "AAPL" }),
+        );
+        assert_eq!(a, b);
+        assert_ne!(a, Cache::key("polygon", "0.1.0", "quote", &json!({ "ticker": "MSFT" })));
+        assert_ne!(
+            a,
+            Cache::key("polygon", "0.1.0", "trades", &json!({ "ticker": "AAPL" }))
+        );
+        assert_ne!(
+            a,
+            Cache::key(
+                "polygon",
+                "0.2.0",
+                "quote",
+                &json!({ "ticker": "AAPL", "range": { "from": 1, "to": 2 } })
+            )
+        );
+
+        let cache = Cache::memory();
+        cache
+            .put("polygon", a.clone(), json!(187.5), Duration::from_secs(60))
+            .await;
+        assert_eq!(cache.get("polygon", &a).await, Some(json!(187.5)));
+        cache.purge("polygon").unwrap();
+        assert_eq!(cache.get("polygon", &a).await, None);
+    }
+}
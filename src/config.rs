@@ -0,0 +1,338 @@
+//! Host configuration for bootstrapping a [`Registry`](crate::Registry).
+//!
+//! ```toml
+//! [extensions.polygon]
+//! config = { api_key = "${POLYGON_API_KEY}", base_url = "https://api.polygon.io" }
+//!
+//! [extensions.alphavantage]
+//! enabled = false
+//!
+//! [trust]
+//! policy = "enforce"
+//! publishers = { inboard = "<hex encoded ed25519 public key>" }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Error;
+use crate::data::Id;
+use crate::signature::{Policy, Trust};
+
+/// The host config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Settings for each extension, by id
+    #[serde(default)]
+    pub extensions: HashMap<Id, ExtensionConfig>,
+    /// Whose signatures to trust, and what to do with components that fail verification
+    #[serde(default)]
+    pub trust: TrustConfig,
+}
+
+/// The `[trust]` table of the host config
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrustConfig {
+    /// `enforce` to refuse components that fail verification, the default, or `warn` to load them anyway
+    #[serde(default)]
+    pub policy: Policy,
+    /// Hex encoded ed25519 public keys, by publisher
+    #[serde(default)]
+    pub publishers: HashMap<String, String>,
+}
+
+/// How the host runs one extension
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtensionConfig {
+    /// Whether to load the extension at all
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Only load a manifest with exactly this version
+    pub version: Option<String>,
+    /// The config passed to the extension. Strings may reference environment variables as `${NAME}`.
+    #[serde(default = "empty")]
+    pub config: Value,
+    /// Values for the `secret` import. These may reference environment variables too.
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    /// Instances of the component to run, each on its own task, so that as many tool calls can be handled at once
+    #[serde(default = "one")]
+    pub instances: usize,
+}
+
+impl Default for ExtensionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version: None,
+            config: empty(),
+            secrets: HashMap::new(),
+            instances: one(),
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn one() -> usize {
+    1
+}
+
+fn empty() -> Value {
+    Value::Object(Default::default())
+}
+
+impl HostConfig {
+    /// Parse a host config from TOML
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        toml::from_str(toml).map_err(|e| Error::ConfigError(e.to_string().trim_end().to_string()))
+    }
+
+    /// Read and parse the host config file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+
+        Self::parse(&content).map_err(|e| match e {
+            Error::ConfigError(message) => Error::ConfigError(format!("{}: {}", path.display(), message)),
+            e => e,
+        })
+    }
+
+    /// The settings for `id`, or the defaults if the file has none
+    pub fn extension(&self, id: &str) -> ExtensionConfig {
+        self.extensions.get(id).cloned().unwrap_or_default()
+    }
+
+    /// The publishers to trust, as the `[trust]` table says
+    pub fn trust(&self) -> Result<Trust, Error> {
+        let mut publishers: Vec<_> = self.trust.publishers.iter().collect();
+        publishers.sort();
+
+        publishers
+            .into_iter()
+            .try_fold(Trust::new(self.trust.policy), |trust, (publisher, key)| {
+                trust.with_hex_key(publisher.clone(), key)
+            })
+    }
+}
+
+impl ExtensionConfig {
+    /// The config with every `${NAME}` replaced, and the top-level fields that referenced the environment.
+    ///
+    /// Those fields usually hold secrets, so they are treated as sensitive.
+    pub fn resolve(&self) -> Result<(Value, Vec<String>), Error> {
+        let mut sensitive = Vec::new();
+        let config = match &self.config {
+            Value::Object(fields) => {
+                let mut resolved = serde_json::Map::new();
+                for (name, value) in fields {
+                    let (value, interpolated) = interpolate_value(value)?;
+                    if interpolated {
+                        sensitive.push(name.clone());
+                    }
+                    resolved.insert(name.clone(), value);
+                }
+                Value::Object(resolved)
+            }
+            value => interpolate_value(value)?.0,
+        };
+
+        Ok((config, sensitive))
+    }
+
+    /// The secrets with every `${NAME}` replaced
+    pub fn resolve_secrets(&self) -> Result<HashMap<String, String>, Error> {
+        self.secrets
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), interpolate(value)?.0)))
+            .collect()
+    }
+}
+
+/// Replace every `${NAME}` in `text` with the environment variable `NAME`.
+///
+/// `$${` is left as a literal `${`. Returns whether anything was replaced.
+pub fn interpolate(text: &str) -> Result<(String, bool), Error> {
+    let mut result = String::with_capacity(text.len());
+    let mut interpolated = false;
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| Error::ConfigError(format!("Unterminated ${{ in {:?}", text)))?;
+            let name = &reference[..end];
+            let value = std::env::var(name)
+                .map_err(|_| Error::ConfigError(format!("Environment variable {} is not set", name)))?;
+
+            result.push_str(&value);
+            interpolated = true;
+            rest = &reference[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+
+    Ok((result, interpolated))
+}
+
+fn interpolate_value(value: &Value) -> Result<(Value, bool), Error> {
+    Ok(match value {
+        Value::String(text) => {
+            let (text, interpolated) = interpolate(text)?;
+            (Value::String(text), interpolated)
+        }
+        Value::Array(items) => {
+            let mut any = false;
+            let items = items
+                .iter()
+                .map(|item| {
+                    let (item, interpolated) = interpolate_value(item)?;
+                    any |= interpolated;
+                    Ok(item)
+                })
+                .collect::<Result<_, Error>>()?;
+            (Value::Array(items), any)
+        }
+        Value::Object(fields) => {
+            let mut any = false;
+            let fields = fields
+                .iter()
+                .map(|(name, value)| {
+                    let (value, interpolated) = interpolate_value(value)?;
+                    any |= interpolated;
+                    Ok((name.clone(), value))
+                })
+                .collect::<Result<_, Error>>()?;
+            (Value::Object(fields), any)
+        }
+        value => (value.clone(), false),
+    })
+}
+
+/// An extension that was loaded and registered
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub id: Id,
+    pub version: String,
+    pub path: PathBuf,
+}
+
+/// An extension or directory that could not be loaded, e.g. a component whose signature was refused
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// The extension, if the failure concerns one
+    pub id: Option<Id>,
+    /// The component or directory, if the failure concerns one
+    pub path: Option<PathBuf>,
+    pub error: Error,
+}
+
+/// What happened when bootstrapping a registry
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub loaded: Vec<Loaded>,
+    pub failed: Vec<Failure>,
+    /// Extensions that were found but disabled in the host config
+    pub disabled: Vec<Id>,
+}
+
+impl LoadReport {
+    /// Whether everything loaded
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Loaded {} extensions, {} failed, {} disabled",
+            self.loaded.len(),
+            self.failed.len(),
+            self.disabled.len()
+        )?;
+        for loaded in &self.loaded {
+            write!(f, "\n  + {} v{} ({})", loaded.id, loaded.version, loaded.path.display())?;
+        }
+        for failure in &self.failed {
+            let what = match (&failure.id, &failure.path) {
+                (Some(id), _) => id.clone(),
+                (None, Some(path)) => path.display().to_string(),
+                (None, None) => "?".to_string(),
+            };
+            write!(f, "\n  ! {}: {}", what, failure.error)?;
+        }
+        for id in &self.disabled {
+            write!(f, "\n  - {} (disabled)", id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_interpolate_config() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("EMPORIUM_TEST_API_KEY", "sk_test_1234") };
+
+        let host = HostConfig::parse(
+            r#"
+            [extensions.polygon]
+            config = { api_key = "${EMPORIUM_TEST_API_KEY}", base_url = "https://api.polygon.io" }
+            secrets = { token = "Bearer ${EMPORIUM_TEST_API_KEY}" }
+            instances = 4
+
+            [extensions.kv]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+
+        let polygon = host.extension("polygon");
+        let (config, sensitive) = polygon.resolve().unwrap();
+        assert_eq!(
+            config,
+            json!({ "api_key": "sk_test_1234", "base_url": "https://api.polygon.io" })
+        );
+        assert_eq!(sensitive, vec!["api_key".to_string()]);
+        assert_eq!(polygon.resolve_secrets().unwrap()["token"], "Bearer sk_test_1234");
+
+        assert!(!host.extension("kv").enabled);
+        assert_eq!((polygon.instances, host.extension("kv").instances), (4, 1));
+        assert!(host.extension("alphavantage").enabled);
+
+        assert_eq!(
+            interpolate("cost: $5, $${HOME}").unwrap(),
+            ("cost: $5, ${HOME}".to_string(), false)
+        );
+        assert!(matches!(
+            interpolate("${EMPORIUM_TEST_UNSET}"),
+            Err(Error::ConfigError(_))
+        ));
+        assert!(HostConfig::parse("[extensions.kv]\nenabeld = true").is_err());
+    }
+}
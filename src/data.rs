@@ -26,6 +26,19 @@ pub enum Command {
 
     /// Any custom command
     Custom(String),
+
+    /// Get the current state of the extension instance, as returned by its `view` function.
+    ///
+    /// The runtime answers this itself with [`Response::Data`]; it is never passed to `update`.
+    View,
+
+    /// An event published on a topic the extension subscribes to
+    Event {
+        topic: String,
+        /// The extension that published it, or `None` for the host
+        source: Option<Id>,
+        payload: serde_json::Value,
+    },
 }
 
 /// Tool information provided by an extension
@@ -39,6 +52,12 @@ pub struct ToolInfo {
     pub description: String,
     /// JSON Schema for the tool's parameters
     pub schema: serde_json::Value,
+    /// Whether the host may cache results, as they only depend on the parameters for a while
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cacheable: bool,
+    /// How long results stay valid, in seconds. Without it the cache's own TTL applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<u64>,
 }
 
 /// A response received FROM an extension
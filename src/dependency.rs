@@ -0,0 +1,251 @@
+//! Check what an extension requires of the host and of other extensions, and load extensions in dependency order.
+//!
+//! ```toml
+//! [dependencies]
+//! runtime = "wasmtime"
+//! minimum_version = "0.1.0" # of the extension API, `emporium:extensions`
+//!
+//! [dependencies.extensions]
+//! polygon = "^0.1"          # an extension id and a semver requirement
+//! ```
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use semver::{Version, VersionReq};
+
+use crate::data::Id;
+use crate::error::DependencyError;
+use crate::extension::Manifest;
+
+/// The runtime extensions are hosted on
+pub const RUNTIME: &str = "wasmtime";
+
+/// The version of the `emporium:extensions` API this host provides, as declared by the package in `wit/`
+pub const API_VERSION: &str = "0.1.0";
+
+/// The `[dependencies]` section of a manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dependencies {
+    pub runtime: Option<String>,
+    /// The oldest extension API version the extension works with
+    pub minimum_version: Option<String>,
+    /// Requirements on other extensions, by id
+    pub extensions: BTreeMap<Id, String>,
+}
+
+/// Check `manifest` against the host and the extension versions in `available`
+pub fn check(manifest: &Manifest, available: &HashMap<Id, String>) -> Result<(), DependencyError> {
+    check_host(manifest)?;
+
+    for (dependency, requirement) in &manifest.dependencies.extensions {
+        let found = available
+            .get(dependency)
+            .ok_or_else(|| DependencyError::Missing(manifest.id.clone(), dependency.clone(), requirement.clone()))?;
+        if !matches(manifest, requirement, found)? {
+            return Err(DependencyError::Version(
+                manifest.id.clone(),
+                dependency.clone(),
+                requirement.clone(),
+                found.clone(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the runtime and API version `manifest` requires
+fn check_host(manifest: &Manifest) -> Result<(), DependencyError> {
+    let dependencies = &manifest.dependencies;
+
+    if let Some(runtime) = &dependencies.runtime
+        && runtime != RUNTIME
+    {
+        return Err(DependencyError::UnsupportedRuntime(
+            manifest.id.clone(),
+            runtime.clone(),
+        ));
+    }
+
+    if let Some(minimum) = &dependencies.minimum_version {
+        let requirement = format!(">={minimum}");
+        if !matches(manifest, &requirement, API_VERSION)? {
+            return Err(DependencyError::HostTooOld(
+                manifest.id.clone(),
+                requirement,
+                API_VERSION.to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn matches(manifest: &Manifest, requirement: &str, version: &str) -> Result<bool, DependencyError> {
+    let invalid = |reason: String| DependencyError::Invalid(manifest.id.clone(), requirement.to_string(), reason);
+
+    let requirement = VersionReq::parse(requirement).map_err(|e| invalid(e.to_string()))?;
+    let version = Version::parse(version).map_err(|e| invalid(format!("version {}: {}", version, e)))?;
+
+    Ok(requirement.matches(&version))
+}
+
+/// Order `manifests` so that every extension comes after the extensions it depends on.
+///
+/// Extensions in `registered` are already loaded and satisfy dependencies as they are. Extensions whose requirements
+/// cannot be met, or that depend on one that cannot be loaded, are returned separately with the reason. Otherwise
+/// the input order is kept.
+pub fn order<'a>(
+    manifests: &[&'a Manifest],
+    registered: &HashMap<Id, String>,
+) -> (Vec<&'a Manifest>, Vec<(Id, DependencyError)>) {
+    let mut available = registered.clone();
+    for manifest in manifests {
+        available
+            .entry(manifest.id.clone())
+            .or_insert_with(|| manifest.version.clone());
+    }
+
+    let mut refused: Vec<(Id, DependencyError)> = Vec::new();
+    let mut remaining: Vec<&Manifest> = Vec::new();
+    for manifest in manifests {
+        match check(manifest, &available) {
+            Ok(()) => remaining.push(manifest),
+            Err(e) => refused.push((manifest.id.clone(), e)),
+        }
+    }
+
+    let mut ordered: Vec<&Manifest> = Vec::new();
+    loop {
+        let loaded = |id: &Id| registered.contains_key(id) || ordered.iter().any(|m| m.id == *id);
+        let failed = |id: &Id| refused.iter().any(|(refused, _)| refused == id);
+
+        // Extensions that depend on one that will not load cannot load either
+        if let Some(index) = remaining
+            .iter()
+            .position(|m| m.dependencies.extensions.keys().any(|d| failed(d) && !loaded(d)))
+        {
+            let manifest = remaining.remove(index);
+            let dependency = manifest.dependencies.extensions.keys().find(|d| failed(d)).unwrap();
+            refused.push((
+                manifest.id.clone(),
+                DependencyError::Refused(manifest.id.clone(), dependency.clone()),
+            ));
+            continue;
+        }
+
+        match remaining
+            .iter()
+            .position(|m| m.dependencies.extensions.keys().all(loaded))
+        {
+            Some(index) => ordered.push(remaining.remove(index)),
+            None => break,
+        }
+    }
+
+    // Whatever is left waits on a cycle, but only the extensions on one are part of it
+    let reached: Vec<HashSet<&str>> = remaining.iter().map(|m| reachable(m, &remaining)).collect();
+    for (manifest, reach) in remaining.iter().zip(&reached) {
+        let id = manifest.id.as_str();
+        if reach.contains(id) {
+            let members: Vec<&str> = remaining
+                .iter()
+                .zip(&reached)
+                .filter(|(other, back)| reach.contains(other.id.as_str()) && back.contains(id))
+                .map(|(other, _)| other.id.as_str())
+                .collect();
+            refused.push((manifest.id.clone(), DependencyError::Cycle(members.join(", "))));
+        } else {
+            let dependency = manifest
+                .dependencies
+                .extensions
+                .keys()
+                .find(|d| remaining.iter().any(|m| m.id == **d))
+                .unwrap();
+            refused.push((
+                manifest.id.clone(),
+                DependencyError::Refused(manifest.id.clone(), dependency.clone()),
+            ));
+        }
+    }
+
+    (ordered, refused)
+}
+
+/// Ids of the extensions among `manifests` that `manifest` depends on, directly or not
+fn reachable<'a>(manifest: &'a Manifest, manifests: &[&'a Manifest]) -> HashSet<&'a str> {
+    let mut reached = HashSet::new();
+    let mut pending: Vec<&str> = manifest.dependencies.extensions.keys().map(String::as_str).collect();
+    while let Some(id) = pending.pop() {
+        if let Some(found) = manifests.iter().find(|m| m.id == id)
+            && reached.insert(found.id.as_str())
+        {
+            pending.extend(found.dependencies.extensions.keys().map(String::as_str));
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(id: &str, version: &str, dependencies: &[(&str, &str)]) -> Manifest {
+        Manifest {
+            id: id.to_string(),
+            name: id.to_string(),
+            version: version.to_string(),
+            component_entry: "extension.wasm".to_string(),
+            dependencies: Dependencies {
+                runtime: Some("wasmtime".to_string()),
+                minimum_version: Some("0.1.0".to_string()),
+                extensions: dependencies
+                    .iter()
+                    .map(|(id, req)| (id.to_string(), req.to_string()))
+                    .collect(),
+            },
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        // The API version is the version of the WIT package extensions are built against
+        let package = format!("package emporium:extensions@{};", API_VERSION);
+        assert!(include_str!("../wit/extension.wit").contains(&package));
+
+        let strategy = manifest("strategy", "1.0.0", &[("polygon", "^0.1"), ("kv", ">=0.1")]);
+        let polygon = manifest("polygon", "0.1.3", &[]);
+        let kv = manifest("kv", "0.1.0", &[]);
+        let (ordered, refused) = order(&[&strategy, &polygon, &kv], &HashMap::new());
+        let ids: Vec<&str> = ordered.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, vec!["polygon", "kv", "strategy"]);
+        assert!(refused.is_empty());
+
+        // Already registered extensions satisfy dependencies too
+        let registered = HashMap::from([("polygon".to_string(), "0.2.0".to_string())]);
+        let (ordered, refused) = order(&[&strategy, &kv], &registered);
+        assert!(ordered.iter().all(|m| m.id == "kv"));
+        assert!(matches!(&refused[0], (id, DependencyError::Version(..)) if id == "strategy"));
+
+        let mut future = manifest("future", "0.1.0", &[]);
+        future.dependencies.minimum_version = Some("2.0.0".to_string());
+        let dependent = manifest("dependent", "0.1.0", &[("future", "*")]);
+        let a = manifest("a", "0.1.0", &[("b", "*")]);
+        let b = manifest("b", "0.1.0", &[("a", "*")]);
+        let c = manifest("c", "0.1.0", &[("a", "*")]);
+        let (ordered, refused) = order(&[&dependent, &future, &c, &a, &b], &HashMap::new());
+        assert!(ordered.is_empty());
+        let reasons: Vec<String> = refused.iter().map(|(_, e)| e.to_string()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "Extension future requires extension API >=2.0.0, but this host provides 0.1.0",
+                "Extension dependent depends on future, which cannot be loaded",
+                // c depends on the cycle without being on it
+                "Extension c depends on a, which cannot be loaded",
+                "Extensions a, b depend on each other",
+                "Extensions a, b depend on each other",
+            ]
+        );
+    }
+}
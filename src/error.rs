@@ -18,10 +18,28 @@ pub enum Error {
     SendError(TrySendError<Command>),
     #[error("Extension not found: {0}")]
     ExtensionNotFound(String),
+    #[error("Extension exited: {0}")]
+    ExtensionExited(String),
+    #[error("Extension unregistered: {0}")]
+    ExtensionUnregistered(String),
     #[error("Extension load error: {0}")]
     ExtensionLoadError(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("State error: {0}")]
+    StateError(String),
+    #[error("Pipeline error: {0}")]
+    PipelineError(String),
     #[error("Manifest error: {0}")]
     ManifestError(ManifestError),
+    #[error("Signature error: {0}")]
+    SignatureError(SignatureError),
+    #[error("Dependency error: {0}")]
+    DependencyError(DependencyError),
     #[error("{0}")]
     Custom(String),
 }
@@ -30,8 +48,40 @@ pub enum Error {
 pub enum ManifestError {
     #[error("Manifest read error: {0}")]
     ReadError(String),
-    #[error("Missing {0}: {1}")]
-    Missing(String, String),
+    #[error("Manifest format {0} is newer than this host reads ({1})")]
+    Format(u32, u32),
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Extension {0} is not signed")]
+    Unsigned(String),
+    #[error("Extension {0} does not match the hash in its manifest")]
+    Tampered(String),
+    #[error("Extension {0} is signed by untrusted publisher {1}")]
+    Untrusted(String, String),
+    #[error("Extension {0} has an invalid signature: {1}")]
+    Invalid(String, String),
+    #[error("Invalid key for publisher {0}: {1}")]
+    InvalidKey(String, String),
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum DependencyError {
+    #[error("Extension {0} requires the {1} runtime, but this host runs wasmtime")]
+    UnsupportedRuntime(String, String),
+    #[error("Extension {0} requires extension API {1}, but this host provides {2}")]
+    HostTooOld(String, String, String),
+    #[error("Extension {0} depends on {1} {2}, which is not available")]
+    Missing(String, String, String),
+    #[error("Extension {0} depends on {1} {2}, but version {3} is available")]
+    Version(String, String, String, String),
+    #[error("Extension {0} depends on {1}, which cannot be loaded")]
+    Refused(String, String),
+    #[error("Extensions {0} depend on each other")]
+    Cycle(String),
+    #[error("Extension {0} has an invalid version requirement {1:?}: {2}")]
+    Invalid(String, String, String),
 }
 
 impl From<std::io::Error> for Error {
@@ -52,6 +102,18 @@ impl From<ManifestError> for Error {
     }
 }
 
+impl From<DependencyError> for Error {
+    fn from(err: DependencyError) -> Self {
+        Error::DependencyError(err)
+    }
+}
+
+impl From<SignatureError> for Error {
+    fn from(err: SignatureError) -> Self {
+        Error::SignatureError(err)
+    }
+}
+
 impl From<TrySendError<Command>> for Error {
     fn from(err: TrySendError<Command>) -> Self {
         Error::SendError(err)
@@ -1,22 +1,76 @@
 //! Host any number of [`Extension`](crate::Extension)s.
 use crate::Error;
 use crate::data::Id;
+use crate::dependency::Dependencies;
 use crate::error::ManifestError;
+use crate::signature::Signature;
 use futures::TryStreamExt;
+use serde::Deserialize;
 use sipper::{Sender, Straw, sipper};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio_stream::wrappers::ReadDirStream;
 
-#[derive(Debug, Clone)]
+/// The version of the manifest format this host reads, set as `manifest_version` at the top of a manifest
+pub const MANIFEST_FORMAT: u32 = 1;
+
+/// An extension's `manifest.toml`
+#[derive(Debug, Clone, Default)]
 pub struct Manifest {
+    /// The `manifest_version` it was written in
+    pub format: u32,
     pub id: Id,
     pub name: String,
     pub version: String,
     pub description: String,
+    pub author: Option<String>,
+    pub company: Option<String>,
+    pub license: Option<String>,
+    /// The company, or else the author
     pub provider: String,
     pub schema: serde_json::Value,
     pub component_entry: String,
+    /// The WIT world the component implements
+    pub world: Option<String>,
+    /// Resources the component exports, by name, from the `[resources]` section
+    pub resources: BTreeMap<String, String>,
+    pub operations: Operations,
+    /// Capabilities enabled in the `[capabilities]` section
+    pub capabilities: Vec<String>,
+    /// Tools of other extensions this one may call, from the `[calls]` section.
+    ///
+    /// Each entry is an extension id, allowing any of its tools, or a qualified tool id like `polygon.get_quote`.
+    pub calls: Vec<String>,
+    /// Topic patterns from `subscribe` in the `[events]` section
+    pub subscriptions: Vec<String>,
+    pub dependencies: Dependencies,
+    pub signature: Option<Signature>,
+    /// Fields this host does not know, which it ignored
+    pub warnings: Vec<String>,
+}
+
+/// The `[operations]` section of a manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Operations {
+    /// Whether a host must support every operation
+    #[serde(default)]
+    pub required: bool,
+    /// Descriptions of the operations, by name
+    #[serde(flatten)]
+    pub descriptions: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Whether the `[calls]` section allows calling `tool_id` on `extension_id`
+    pub fn may_call(&self, extension_id: &str, tool_id: &str) -> bool {
+        self.calls
+            .iter()
+            .any(|call| match call.split_once(crate::registry::NAMESPACE_SEPARATOR) {
+                Some((extension, tool)) => extension == extension_id && tool == tool_id,
+                None => call == extension_id,
+            })
+    }
 }
 
 pub type Entry = (PathBuf, Manifest);
@@ -25,7 +79,7 @@ pub type Result = std::result::Result<(), Error>;
 /// Find extensions in a directory recursively (max 2 levels deep).
 pub fn list(extensions_dir: impl AsRef<Path>) -> impl Straw<(), Entry, Error> {
     sipper(move |mut sender| async move {
-        scan_directory(&extensions_dir.as_ref(), 0, 2, &mut sender).await?;
+        scan_directory(extensions_dir.as_ref(), 0, 2, &mut sender).await?;
         Ok(())
     })
 }
@@ -54,16 +108,25 @@ fn scan_directory<'a>(
             } else if path.file_name().and_then(|n| n.to_str()) == Some("manifest.toml") {
                 // Found a manifest file - try to parse it
                 eprintln!("Found manifest.toml at {}", path.display());
-                if let Ok(manifest) = parse_manifest(&path).await {
-                    // Verify the wasm file exists
-                    let extension_dir = path.parent().unwrap();
-                    let wasm_path = extension_dir.join(&manifest.component_entry);
-
-                    if wasm_path.exists() {
-                        sender.send((wasm_path, manifest)).await;
-                    } else {
-                        eprintln!("Wasm file not found at {}", wasm_path.display());
+                let manifest = match parse_manifest(&path).await {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        eprintln!("Skipping {}: {}", path.display(), e);
+                        continue;
                     }
+                };
+                for warning in &manifest.warnings {
+                    eprintln!("{}: {}", path.display(), warning);
+                }
+
+                // Verify the wasm file exists
+                let extension_dir = path.parent().unwrap();
+                let wasm_path = extension_dir.join(&manifest.component_entry);
+
+                if wasm_path.exists() {
+                    sender.send((wasm_path, manifest)).await;
+                } else {
+                    eprintln!("Wasm file not found at {}", wasm_path.display());
                 }
             }
         }
@@ -73,57 +136,274 @@ fn scan_directory<'a>(
 }
 
 /// Parse a manifest.toml file
-async fn parse_manifest(manifest_path: &Path) -> std::result::Result<Manifest, Error> {
+pub(crate) async fn parse_manifest(manifest_path: &Path) -> std::result::Result<Manifest, Error> {
     let content = fs::read_to_string(manifest_path)
         .await
         .map_err(|e| ManifestError::ReadError(format!("Failed to read manifest: {}", e)))?;
 
-    let toml: toml::Value =
-        toml::from_str(&content).map_err(|e| ManifestError::ReadError(format!("Failed to parse TOML: {}", e)))?;
-
-    let missing =
-        |section: &str, field: &str| Error::from(ManifestError::Missing(section.to_string(), field.to_string()));
-
-    let extension = toml.get("extension").ok_or_else(|| missing("section", "extension"))?;
-    let component = toml.get("component").ok_or_else(|| missing("section", "component"))?;
-    let config = toml.get("config").ok_or_else(|| missing("section", "config"))?;
-
-    Ok(Manifest {
-        id: extension
-            .get("id")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| missing("extension", "id"))?
-            .to_string(),
-        name: extension
-            .get("name")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| missing("extension", "name"))?
-            .to_string(),
-        version: extension
-            .get("version")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| missing("extension", "version"))?
-            .to_string(),
-        description: extension
-            .get("description")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string(),
-        provider: extension
-            .get("company")
-            .and_then(|v| v.as_str())
-            .or_else(|| extension.get("author").and_then(|v| v.as_str()))
-            .unwrap_or("")
-            .to_string(),
-        component_entry: component
-            .get("entry")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| missing("component", "entry"))?
-            .to_string(),
-        schema: config
-            .get("schema")
-            .and_then(|s| s.as_str())
-            .and_then(|s| serde_json::from_str(s).ok())
-            .unwrap_or_else(|| serde_json::json!({})),
-    })
+    Manifest::parse(&content)
+}
+
+impl Manifest {
+    /// Parse the content of a manifest.toml file.
+    ///
+    /// Unknown sections and fields do not fail parsing, they end up in [`warnings`](Self::warnings).
+    pub fn parse(toml: &str) -> std::result::Result<Self, Error> {
+        let invalid = |e: toml::de::Error| ManifestError::ReadError(format!("Failed to parse TOML: {}", e));
+
+        // Check the format first, as a newer one may not parse as this one
+        let Format { manifest_version } = toml::from_str(toml).map_err(invalid)?;
+        let format = manifest_version.unwrap_or(MANIFEST_FORMAT);
+        if format > MANIFEST_FORMAT {
+            return Err(ManifestError::Format(format, MANIFEST_FORMAT).into());
+        }
+
+        let mut file: File = toml::from_str(toml).map_err(invalid)?;
+        file.unknown.remove("manifest_version");
+
+        let mut warnings = Vec::new();
+        let mut unknown = |section: &str, fields: &BTreeMap<String, toml::Value>| {
+            warnings.extend(fields.keys().map(|field| match section {
+                "" => format!("Unknown section or field {}", field),
+                section => format!("Unknown field {}.{}", section, field),
+            }));
+        };
+        unknown("", &file.unknown);
+        unknown("extension", &file.extension.unknown);
+        unknown("component", &file.component.unknown);
+        unknown("config", &file.config.unknown);
+        unknown("events", &file.events.unknown);
+        unknown("dependencies", &file.dependencies.unknown);
+        if let Some(signature) = &file.signature {
+            unknown("signature", &signature.unknown);
+        }
+
+        let schema = match &file.config.schema {
+            Some(schema) => serde_json::from_str(schema).unwrap_or_else(|e| {
+                warnings.push(format!("Ignored config.schema, which is not valid JSON: {}", e));
+                serde_json::json!({})
+            }),
+            None => serde_json::json!({}),
+        };
+
+        // `polygon = true` allows every tool of polygon, `polygon = ["get_quote"]` only those listed
+        let calls = file
+            .calls
+            .into_iter()
+            .flat_map(|(extension, tools)| match tools {
+                Calls::All(true) => vec![extension],
+                Calls::All(false) => vec![],
+                Calls::Tools(tools) => tools
+                    .iter()
+                    .map(|tool| format!("{}{}{}", extension, crate::registry::NAMESPACE_SEPARATOR, tool))
+                    .collect(),
+            })
+            .collect();
+
+        let extension = file.extension;
+        Ok(Manifest {
+            format,
+            provider: extension
+                .company
+                .clone()
+                .or_else(|| extension.author.clone())
+                .unwrap_or_default(),
+            id: extension.id,
+            name: extension.name,
+            version: extension.version,
+            description: extension.description,
+            author: extension.author,
+            company: extension.company,
+            license: extension.license,
+            schema,
+            component_entry: file.component.entry,
+            world: file.component.world,
+            resources: file.resources,
+            operations: file.operations,
+            capabilities: file
+                .capabilities
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name)
+                .collect(),
+            calls,
+            subscriptions: file.events.subscribe,
+            dependencies: Dependencies {
+                runtime: file.dependencies.runtime,
+                minimum_version: file.dependencies.minimum_version,
+                extensions: file.dependencies.extensions,
+            },
+            signature: file.signature.map(|signature| Signature {
+                sha256: signature.sha256,
+                publisher: signature.publisher,
+                ed25519: signature.ed25519,
+            }),
+            warnings,
+        })
+    }
+}
+
+/// Just the format of a manifest
+#[derive(Deserialize)]
+struct Format {
+    manifest_version: Option<u32>,
+}
+
+/// A manifest as written. Each section keeps the fields it does not know, to warn about them.
+#[derive(Deserialize)]
+struct File {
+    extension: ExtensionSection,
+    component: ComponentSection,
+    config: ConfigSection,
+    #[serde(default)]
+    resources: BTreeMap<String, String>,
+    #[serde(default)]
+    operations: Operations,
+    #[serde(default)]
+    capabilities: BTreeMap<String, bool>,
+    #[serde(default)]
+    calls: BTreeMap<String, Calls>,
+    #[serde(default)]
+    events: EventsSection,
+    #[serde(default)]
+    dependencies: DependenciesSection,
+    signature: Option<SignatureSection>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+struct ExtensionSection {
+    id: Id,
+    name: String,
+    version: String,
+    #[serde(default)]
+    description: String,
+    author: Option<String>,
+    company: Option<String>,
+    license: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+struct ComponentSection {
+    entry: String,
+    world: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+struct ConfigSection {
+    /// A JSON schema, as a string
+    schema: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// The tools of one extension in the `[calls]` section
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Calls {
+    All(bool),
+    Tools(Vec<String>),
+}
+
+#[derive(Default, Deserialize)]
+struct EventsSection {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Default, Deserialize)]
+struct DependenciesSection {
+    runtime: Option<String>,
+    minimum_version: Option<String>,
+    /// Requirements on other extensions, by id, from `[dependencies.extensions]`
+    #[serde(default)]
+    extensions: BTreeMap<Id, String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+struct SignatureSection {
+    sha256: String,
+    publisher: String,
+    ed25519: String,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let kv = Manifest::parse(include_str!("../marketplace/kv/rust/manifest.toml")).unwrap();
+        assert_eq!(kv.format, MANIFEST_FORMAT);
+        assert_eq!((kv.id.as_str(), kv.version.as_str()), ("kv", "0.1.0"));
+        assert_eq!(kv.author.as_deref(), Some("Andy Terra"));
+        assert_eq!(kv.provider, "inboard AI");
+        assert_eq!(kv.license.as_deref(), Some("MIT"));
+        assert_eq!(kv.world.as_deref(), Some("kv-provider"));
+        assert_eq!(kv.resources["store"], "emporium:kv/kv-store/store");
+        assert_eq!(kv.operations.descriptions.len(), 6);
+        assert!(!kv.operations.required);
+        assert_eq!(kv.capabilities, vec!["events", "networking", "storage"]);
+        assert_eq!(kv.dependencies.runtime.as_deref(), Some("wasmtime"));
+        assert!(kv.schema["properties"]["initial_data"].is_object());
+        assert!(kv.warnings.is_empty());
+
+        let manifest = r#"
+            manifest_version = 1
+            homepage = "https://example.com"
+
+            [extension]
+            id = "strategy"
+            name = "Strategy"
+            version = "1.0.0"
+            author = "Someone"
+            icon = "icon.png"
+
+            [component]
+            entry = "strategy.wasm"
+
+            [config]
+
+            [calls]
+            polygon = ["get_quote"]
+            kv = true
+
+            [dependencies]
+            minimum_verison = "0.1.0"
+
+            [dependencies.extensions]
+            polygon = "^0.1"
+        "#;
+        let strategy = Manifest::parse(manifest).unwrap();
+        assert_eq!(strategy.provider, "Someone");
+        assert_eq!(strategy.calls, vec!["kv", "polygon.get_quote"]);
+        assert_eq!(strategy.dependencies.extensions["polygon"], "^0.1");
+        assert_eq!(
+            strategy.warnings,
+            vec![
+                "Unknown section or field homepage",
+                "Unknown field extension.icon",
+                "Unknown field dependencies.minimum_verison"
+            ]
+        );
+
+        let newer = manifest.replace("manifest_version = 1", "manifest_version = 2");
+        assert!(matches!(
+            Manifest::parse(&newer),
+            Err(Error::ManifestError(ManifestError::Format(2, 1)))
+        ));
+        let incomplete = manifest.replace("entry = \"strategy.wasm\"", "");
+        assert!(Manifest::parse(&incomplete).is_err());
+    }
 }
@@ -0,0 +1,276 @@
+//! Host-side handling of the WASI filesystem calls made by extensions.
+//!
+//! Every call goes to the `wasmtime-wasi` implementation. Opening a file or directory is also recorded in the
+//! extension's audit trail, with the path it asked for and the mode it asked for it in.
+use wasmtime::component::{Linker, Resource};
+use wasmtime_wasi::bindings::filesystem::types::{
+    self, DescriptorFlags, ErrorCode, HostDescriptor, HostDirectoryEntryStream, OpenFlags,
+};
+use wasmtime_wasi::bindings::io::streams::{InputStream, OutputStream};
+use wasmtime_wasi::{FsError, FsResult, WasiImpl};
+
+use crate::audit::Event;
+use crate::wasm::State;
+
+/// The WASI filesystem of one extension
+struct Filesystem<'a>(WasiImpl<&'a mut State>);
+
+fn filesystem(state: &mut State) -> Filesystem<'_> {
+    Filesystem(WasiImpl(state))
+}
+
+/// Replace the `wasi:filesystem/types` interface in `linker`, which already has the rest of WASI, with one that
+/// audits opens
+pub(crate) fn add_to_linker(linker: &mut Linker<State>) -> anyhow::Result<()> {
+    // Shadowing replaces the whole interface, so every function of it is defined again
+    linker.allow_shadowing(true);
+    let added = types::add_to_linker_get_host(linker, filesystem);
+    linker.allow_shadowing(false);
+    added
+}
+
+/// How a file was opened, e.g. `read,write,create`
+fn mode(oflags: OpenFlags, flags: DescriptorFlags) -> String {
+    let named = [
+        (flags.contains(DescriptorFlags::READ), "read"),
+        (flags.contains(DescriptorFlags::WRITE), "write"),
+        (oflags.contains(OpenFlags::CREATE), "create"),
+        (oflags.contains(OpenFlags::EXCLUSIVE), "exclusive"),
+        (oflags.contains(OpenFlags::TRUNCATE), "truncate"),
+        (oflags.contains(OpenFlags::DIRECTORY), "directory"),
+    ];
+
+    named
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[wasmtime_wasi::async_trait]
+impl types::Host for Filesystem<'_> {
+    fn convert_error_code(&mut self, err: FsError) -> anyhow::Result<ErrorCode> {
+        types::Host::convert_error_code(&mut self.0, err)
+    }
+
+    fn filesystem_error_code(&mut self, err: Resource<anyhow::Error>) -> anyhow::Result<Option<ErrorCode>> {
+        types::Host::filesystem_error_code(&mut self.0, err)
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl HostDescriptor for Filesystem<'_> {
+    async fn open_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        oflags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> FsResult<Resource<types::Descriptor>> {
+        let opened = self.0.open_at(fd, path_flags, path.clone(), oflags, flags).await;
+
+        self.0.0.audit(Event::Open {
+            path,
+            mode: mode(oflags, flags),
+            error: opened.as_ref().err().map(|e| e.to_string()),
+        });
+        opened
+    }
+
+    async fn advise(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+        len: types::Filesize,
+        advice: types::Advice,
+    ) -> FsResult<()> {
+        self.0.advise(fd, offset, len, advice).await
+    }
+
+    async fn sync_data(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        self.0.sync_data(fd).await
+    }
+
+    async fn get_flags(&mut self, fd: Resource<types::Descriptor>) -> FsResult<DescriptorFlags> {
+        self.0.get_flags(fd).await
+    }
+
+    async fn get_type(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorType> {
+        self.0.get_type(fd).await
+    }
+
+    async fn set_size(&mut self, fd: Resource<types::Descriptor>, size: types::Filesize) -> FsResult<()> {
+        self.0.set_size(fd, size).await
+    }
+
+    async fn set_times(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        self.0.set_times(fd, atim, mtim).await
+    }
+
+    async fn read(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        len: types::Filesize,
+        offset: types::Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        self.0.read(fd, len, offset).await
+    }
+
+    async fn write(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        buf: Vec<u8>,
+        offset: types::Filesize,
+    ) -> FsResult<types::Filesize> {
+        self.0.write(fd, buf, offset).await
+    }
+
+    async fn read_directory(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<Resource<types::DirectoryEntryStream>> {
+        self.0.read_directory(fd).await
+    }
+
+    async fn sync(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        self.0.sync(fd).await
+    }
+
+    async fn create_directory_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        self.0.create_directory_at(fd, path).await
+    }
+
+    async fn stat(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorStat> {
+        self.0.stat(fd).await
+    }
+
+    async fn stat_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::DescriptorStat> {
+        self.0.stat_at(fd, path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        self.0.set_times_at(fd, path_flags, path, atim, mtim).await
+    }
+
+    async fn link_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path_flags: types::PathFlags,
+        old_path: String,
+        new_descriptor: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        self.0
+            .link_at(fd, old_path_flags, old_path, new_descriptor, new_path)
+            .await
+    }
+
+    fn drop(&mut self, fd: Resource<types::Descriptor>) -> anyhow::Result<()> {
+        HostDescriptor::drop(&mut self.0, fd)
+    }
+
+    async fn readlink_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<String> {
+        self.0.readlink_at(fd, path).await
+    }
+
+    async fn remove_directory_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        self.0.remove_directory_at(fd, path).await
+    }
+
+    async fn rename_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path: String,
+        new_fd: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        self.0.rename_at(fd, old_path, new_fd, new_path).await
+    }
+
+    async fn symlink_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        src_path: String,
+        dest_path: String,
+    ) -> FsResult<()> {
+        self.0.symlink_at(fd, src_path, dest_path).await
+    }
+
+    async fn unlink_file_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        self.0.unlink_file_at(fd, path).await
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<InputStream>> {
+        self.0.read_via_stream(fd, offset)
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<OutputStream>> {
+        self.0.write_via_stream(fd, offset)
+    }
+
+    fn append_via_stream(&mut self, fd: Resource<types::Descriptor>) -> FsResult<Resource<OutputStream>> {
+        self.0.append_via_stream(fd)
+    }
+
+    async fn is_same_object(
+        &mut self,
+        a: Resource<types::Descriptor>,
+        b: Resource<types::Descriptor>,
+    ) -> anyhow::Result<bool> {
+        self.0.is_same_object(a, b).await
+    }
+
+    async fn metadata_hash(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::MetadataHashValue> {
+        self.0.metadata_hash(fd).await
+    }
+
+    async fn metadata_hash_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::MetadataHashValue> {
+        self.0.metadata_hash_at(fd, path_flags, path).await
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl HostDirectoryEntryStream for Filesystem<'_> {
+    async fn read_directory_entry(
+        &mut self,
+        stream: Resource<types::DirectoryEntryStream>,
+    ) -> FsResult<Option<types::DirectoryEntry>> {
+        self.0.read_directory_entry(stream).await
+    }
+
+    fn drop(&mut self, stream: Resource<types::DirectoryEntryStream>) -> anyhow::Result<()> {
+        HostDirectoryEntryStream::drop(&mut self.0, stream)
+    }
+}
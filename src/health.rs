@@ -0,0 +1,158 @@
+//! Probe extensions periodically to tell live ones from stuck ones.
+//!
+//! A probe asks an extension for its [`view`](crate::Command::View). Any idle instance of the extension answers it,
+//! or the first one to finish its command, so an extension whose instances are all stuck on commands misses its
+//! probes like one that stopped answering. Extensions with long-running commands need a longer probe timeout.
+use std::time::{Duration, SystemTime};
+
+/// How an extension is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// Answered the last probe in time
+    Healthy,
+    /// Answered the last probe slowly, or missed fewer probes than it takes to be unresponsive
+    Degraded,
+    /// Missed too many probes in a row
+    Unresponsive,
+}
+
+/// The health of a ready extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub health: Health,
+    /// When the extension last sent anything
+    pub last_seen: SystemTime,
+    /// When the extension last answered a probe
+    pub last_probe: Option<SystemTime>,
+    /// How long the last answered probe took
+    pub latency: Option<Duration>,
+    /// Probes missed in a row
+    pub missed: u32,
+    /// Commands sent to the extension that it has not answered yet, not counting probes
+    pub in_flight: usize,
+}
+
+/// How often and how strictly extensions are probed
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) slow: Duration,
+    pub(crate) unresponsive_after: u32,
+    pub(crate) restart: bool,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            slow: Duration::from_secs(1),
+            unresponsive_after: 3,
+            restart: false,
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Probe every extension this often
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Count a probe as missed if it is not answered within `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Count an extension as degraded if it takes longer than `slow` to answer
+    pub fn slow(mut self, slow: Duration) -> Self {
+        self.slow = slow;
+        self
+    }
+
+    /// Count an extension as unresponsive once it misses `probes` probes in a row
+    pub fn unresponsive_after(mut self, probes: u32) -> Self {
+        self.unresponsive_after = probes.max(1);
+        self
+    }
+
+    /// Restart unresponsive extensions
+    pub fn restart(mut self, restart: bool) -> Self {
+        self.restart = restart;
+        self
+    }
+}
+
+impl Status {
+    /// The status of an extension that just became ready
+    pub(crate) fn new() -> Self {
+        Self {
+            health: Health::Healthy,
+            last_seen: SystemTime::now(),
+            last_probe: None,
+            latency: None,
+            missed: 0,
+            in_flight: 0,
+        }
+    }
+
+    /// Record the outcome of a probe: how long it took to answer, or `None` if it was missed
+    pub(crate) fn probed(&mut self, latency: Option<Duration>, check: &HealthCheck) {
+        match latency {
+            Some(latency) => {
+                let now = SystemTime::now();
+                self.last_seen = now;
+                self.last_probe = Some(now);
+                self.latency = Some(latency);
+                self.missed = 0;
+                self.health = if latency > check.slow {
+                    Health::Degraded
+                } else {
+                    Health::Healthy
+                };
+            }
+            None => {
+                self.missed += 1;
+                self.health = if self.missed >= check.unresponsive_after {
+                    Health::Unresponsive
+                } else {
+                    Health::Degraded
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_outcomes() {
+        let check = HealthCheck::new()
+            .slow(Duration::from_millis(100))
+            .unresponsive_after(2);
+        let mut status = Status::new();
+
+        status.probed(Some(Duration::from_millis(10)), &check);
+        assert_eq!(status.health, Health::Healthy);
+
+        status.probed(Some(Duration::from_millis(500)), &check);
+        assert_eq!(status.health, Health::Degraded);
+
+        status.probed(None, &check);
+        assert_eq!((status.health, status.missed), (Health::Degraded, 1));
+        status.probed(None, &check);
+        assert_eq!(status.health, Health::Unresponsive);
+
+        status.probed(Some(Duration::from_millis(10)), &check);
+        assert_eq!((status.health, status.missed), (Health::Healthy, 0));
+    }
+}
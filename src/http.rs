@@ -0,0 +1,347 @@
+//! Host-side handling of outgoing HTTP requests made by extensions.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig};
+
+use crate::audit::{Audit, Event};
+use crate::data::Id;
+use crate::metrics::Recorder;
+use crate::permission::{Capability, Permissions};
+use crate::redact::Redactor;
+use crate::ttl::Store;
+pub use crate::ttl::{Backend, Stats};
+
+/// An opt-in cache for `GET` responses, keyed per extension.
+///
+/// Responses are stored for the `max-age` given in their `Cache-Control` header, or for the configured TTL when
+/// the server does not say. Responses marked `no-store` or `no-cache` are never stored, and a guest can bypass the
+/// cache by sending `Cache-Control: no-cache` itself.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    store: Store<Entry>,
+    ttl: Option<Duration>,
+}
+
+/// A stored response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+}
+
+impl Cache {
+    /// Create a cache that keeps responses in memory
+    pub fn memory() -> Self {
+        Self::new(Backend::Memory)
+    }
+
+    /// Create a cache that keeps responses on disk under `dir`
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self::new(Backend::Disk(dir.into()))
+    }
+
+    /// Create a cache with the given backend
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            store: Store::new(backend),
+            ttl: None,
+        }
+    }
+
+    /// Cache responses without a `max-age` for `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Counters for a single extension
+    pub fn stats(&self, extension_id: &str) -> Stats {
+        self.store.stats(extension_id)
+    }
+
+    /// Counters for every extension that used the cache
+    pub fn all_stats(&self) -> HashMap<Id, Stats> {
+        self.store.all_stats()
+    }
+
+    /// Drop every stored response
+    pub fn clear(&self) -> std::io::Result<()> {
+        self.store.clear()
+    }
+
+    fn key(extension_id: &str, url: &str) -> String {
+        let digest = Sha256::digest(format!("{extension_id}\n{url}").as_bytes());
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Lifetime of a response, or `None` if it must not be stored
+    fn lifetime(&self, response: &http::Response<impl Sized>) -> Option<Duration> {
+        if response.status() != http::StatusCode::OK {
+            return None;
+        }
+
+        let directives = cache_control(response.headers());
+        if directives.iter().any(|d| d == "no-store" || d == "no-cache") {
+            return None;
+        }
+
+        let max_age = directives
+            .iter()
+            .find_map(|d| d.strip_prefix("max-age="))
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+
+        max_age.or(self.ttl).filter(|ttl| !ttl.is_zero())
+    }
+}
+
+/// The extension an outgoing request is sent for, and the host services it goes through.
+pub(crate) struct Outgoing<'a> {
+    pub extension_id: &'a Id,
+    pub version: &'a str,
+    pub cache: Option<&'a Cache>,
+    pub permissions: Option<&'a Permissions>,
+    pub audit: Option<&'a Audit>,
+    pub metrics: Option<&'a Recorder>,
+    pub redactor: &'a Redactor,
+}
+
+/// Send an outgoing request, checking permissions, going through the cache, auditing it and counting it when those
+/// are set.
+pub(crate) fn send(
+    outgoing: Outgoing<'_>,
+    request: http::Request<HyperOutgoingBody>,
+    config: OutgoingRequestConfig,
+) -> HostFutureIncomingResponse {
+    if outgoing.cache.is_none()
+        && outgoing.permissions.is_none()
+        && outgoing.audit.is_none()
+        && outgoing.metrics.is_none()
+    {
+        return wasmtime_wasi_http::types::default_send_request(request, config);
+    }
+
+    let extension_id = outgoing.extension_id.clone();
+    let version = outgoing.version.to_string();
+    let cache = outgoing.cache.filter(|_| is_cacheable(&request)).cloned();
+    let permissions = outgoing.permissions.cloned();
+    let tally = (outgoing.audit.is_some() || outgoing.metrics.is_some()).then(|| Tally {
+        audit: outgoing.audit.cloned(),
+        metrics: outgoing.metrics.cloned(),
+        redactor: outgoing.redactor.clone(),
+        extension_id: extension_id.clone(),
+        method: request.method().to_string(),
+        url: request.uri().to_string(),
+        status: None,
+        bytes: 0,
+        error: None,
+    });
+
+    let handle = wasmtime_wasi::runtime::spawn(async move {
+        let response = async {
+            if let Some(permissions) = permissions {
+                let authority = request.uri().authority().map(|a| a.host().to_string()).unwrap_or_default();
+                if !permissions.check(&extension_id, &version, Capability::Http { authority }).await {
+                    return Err(ErrorCode::HttpRequestDenied);
+                }
+            }
+
+            match cache {
+                Some(cache) => cached(&extension_id, &cache, request, config).await,
+                None => wasmtime_wasi_http::types::default_send_request_handler(request, config).await,
+            }
+        };
+
+        Ok(match tally {
+            Some(tally) => tally.count(response.await),
+            None => response.await,
+        })
+    });
+
+    HostFutureIncomingResponse::pending(handle)
+}
+
+async fn cached(
+    extension_id: &str,
+    cache: &Cache,
+    request: http::Request<HyperOutgoingBody>,
+    config: OutgoingRequestConfig,
+) -> Result<IncomingResponse, ErrorCode> {
+    let key = Cache::key(extension_id, &request.uri().to_string());
+
+    if let Some(entry) = cache.store.get(extension_id, &key).await {
+        return entry.into_response(config.between_bytes_timeout);
+    }
+
+    let response = wasmtime_wasi_http::types::default_send_request_handler(request, config).await?;
+
+    let Some(lifetime) = cache.lifetime(&response.resp) else {
+        return Ok(response);
+    };
+
+    let IncomingResponse {
+        resp,
+        worker,
+        between_bytes_timeout,
+    } = response;
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await?.to_bytes();
+
+    let entry = Entry {
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect(),
+        body: body.to_vec(),
+    };
+    cache.store.put(extension_id, key, entry, lifetime).await;
+
+    Ok(IncomingResponse {
+        resp: http::Response::from_parts(parts, full(body)),
+        worker,
+        between_bytes_timeout,
+    })
+}
+
+/// Records an HTTP audit event and metrics once the extension is done reading the response body
+struct Tally {
+    audit: Option<Audit>,
+    metrics: Option<Recorder>,
+    redactor: Redactor,
+    extension_id: Id,
+    method: String,
+    url: String,
+    status: Option<u16>,
+    bytes: u64,
+    error: Option<String>,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    fn count(mut self, response: Result<IncomingResponse, ErrorCode>) -> Result<IncomingResponse, ErrorCode> {
+        match response {
+            Ok(response) => {
+                self.status = Some(response.resp.status().as_u16());
+                let (parts, body) = response.resp.into_parts();
+                let body = body
+                    .map_frame(move |frame| {
+                        if let Some(data) = frame.data_ref() {
+                            self.add(data.len());
+                        }
+                        frame
+                    })
+                    .boxed();
+
+                Ok(IncomingResponse {
+                    resp: http::Response::from_parts(parts, body),
+                    ..response
+                })
+            }
+            Err(e) => {
+                self.error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.http(self.bytes);
+        }
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let event = Event::Http {
+            method: std::mem::take(&mut self.method),
+            url: std::mem::take(&mut self.url),
+            status: self.status,
+            bytes: self.bytes,
+            error: self.error.take(),
+        };
+        audit.record(&self.extension_id, self.redactor.event(event));
+    }
+}
+
+impl Entry {
+    fn into_response(self, between_bytes_timeout: Duration) -> Result<IncomingResponse, ErrorCode> {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        let resp = builder
+            .body(full(Bytes::from(self.body)))
+            .map_err(|e| ErrorCode::InternalError(Some(format!("Invalid cached response: {}", e))))?;
+
+        Ok(IncomingResponse {
+            resp,
+            worker: None,
+            between_bytes_timeout,
+        })
+    }
+}
+
+/// Only plain `GET`s that the guest did not ask to revalidate are looked up
+fn is_cacheable<B>(request: &http::Request<B>) -> bool {
+    request.method() == http::Method::GET
+        && !cache_control(request.headers())
+            .iter()
+            .any(|d| d == "no-cache" || d == "no-store")
+}
+
+/// Lowercased `Cache-Control` directives
+fn cache_control(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect()
+}
+
+fn full(bytes: Bytes) -> wasmtime_wasi_http::body::HyperIncomingBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(cache_control: Option<&str>) -> http::Response<()> {
+        let mut builder = http::Response::builder().status(200);
+        if let Some(value) = cache_control {
+            builder = builder.header(http::header::CACHE_CONTROL, value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_lifetime_respects_cache_control() {
+        let cache = Cache::memory().with_ttl(Duration::from_secs(60));
+
+        assert_eq!(cache.lifetime(&response(None)), Some(Duration::from_secs(60)));
+        assert_eq!(
+            cache.lifetime(&response(Some("public, max-age=5"))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(cache.lifetime(&response(Some("no-store"))), None);
+        assert_eq!(cache.lifetime(&response(Some("max-age=0"))), None);
+        assert_eq!(Cache::memory().lifetime(&response(None)), None);
+    }
+}
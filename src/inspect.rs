@@ -0,0 +1,216 @@
+//! Inspect what a component imports and exports before it is instantiated.
+use std::fmt;
+
+use wasmtime::Engine;
+use wasmtime::component::{Component, Linker};
+
+use crate::Error;
+use crate::data::Id;
+use crate::extension::Manifest;
+
+/// Interfaces the runtime links for every extension, with the package version it implements
+const PROVIDED: &[(&str, &str)] = &[
+    ("wasi:cli", "0.2"),
+    ("wasi:clocks", "0.2"),
+    ("wasi:filesystem", "0.2"),
+    ("wasi:io", "0.2"),
+    ("wasi:random", "0.2"),
+    ("wasi:sockets", "0.2"),
+    ("wasi:http", "0.2"),
+    ("emporium:extensions", "0.1"),
+];
+
+/// Top-level functions the runtime links for every extension
+const FUNCTIONS: &[&str] = &["log", "secret", "call-tool", "publish"];
+
+/// Exports every extension must provide
+const REQUIRED_EXPORTS: &[&str] = &["emporium:extensions/extension"];
+
+/// Imports that need a capability granted in the manifest, and the capability names that grant them
+const GRANTS: &[(&str, &[&str])] = &[("wasi:http/outgoing-handler", &["network", "networking"])];
+
+/// An imported or exported item, e.g. `wasi:http/types@0.2.0` or `log`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    /// The name without its version, e.g. `wasi:http/types`
+    pub name: String,
+    pub version: Option<String>,
+}
+
+/// Something about a component the runtime cannot accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// An import the runtime does not provide
+    Unsupported(Interface),
+    /// An import that needs a capability the manifest does not grant
+    NotGranted { import: Interface, capability: String },
+    /// An export the runtime needs but the component does not provide
+    MissingExport(String),
+    /// The linker rejected the component's types
+    Incompatible(String),
+}
+
+/// The imports and exports of a component, and everything wrong with them
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub extension_id: Id,
+    pub imports: Vec<Interface>,
+    pub exports: Vec<Interface>,
+    pub problems: Vec<Problem>,
+}
+
+impl Interface {
+    fn parse(name: &str) -> Self {
+        match name.split_once('@') {
+            Some((name, version)) => Self {
+                name: name.to_string(),
+                version: Some(version.to_string()),
+            },
+            None => Self {
+                name: name.to_string(),
+                version: None,
+            },
+        }
+    }
+
+    /// The package part of the name, e.g. `wasi:http`
+    pub fn package(&self) -> &str {
+        self.name.split('/').next().unwrap_or(&self.name)
+    }
+
+    fn is_provided(&self) -> bool {
+        if FUNCTIONS.contains(&self.name.as_str()) {
+            return true;
+        }
+
+        PROVIDED.iter().any(|(package, compatible)| {
+            self.package() == *package
+                && self
+                    .version
+                    .as_deref()
+                    .is_some_and(|v| v == *compatible || v.starts_with(&format!("{compatible}.")))
+        })
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unsupported(import) => write!(f, "imports {import}, which the runtime does not provide"),
+            Problem::NotGranted { import, capability } => write!(
+                f,
+                "imports {import}, which needs the `{capability}` capability in its manifest"
+            ),
+            Problem::MissingExport(name) => write!(f, "does not export {name}"),
+            Problem::Incompatible(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Report {
+    /// Whether the component can be instantiated
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Turn a report with problems into an [`Error::ExtensionLoadError`] carrying the whole diagnostic
+    pub fn into_result(self) -> Result<Self, Error> {
+        if self.is_ok() {
+            Ok(self)
+        } else {
+            Err(Error::ExtensionLoadError(self.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "Extension {} is valid", self.extension_id);
+        }
+
+        write!(f, "Extension {} cannot be loaded:", self.extension_id)?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Inspect `component` against what `linker` provides and, when given, what `manifest` grants
+pub(crate) fn inspect<T>(
+    extension_id: &Id,
+    engine: &Engine,
+    component: &Component,
+    linker: &Linker<T>,
+    manifest: Option<&Manifest>,
+) -> Report {
+    let ty = component.component_type();
+    let imports: Vec<Interface> = ty.imports(engine).map(|(name, _)| Interface::parse(name)).collect();
+    let exports: Vec<Interface> = ty.exports(engine).map(|(name, _)| Interface::parse(name)).collect();
+
+    let mut problems: Vec<Problem> = imports
+        .iter()
+        .filter(|import| !import.is_provided())
+        .map(|import| Problem::Unsupported(import.clone()))
+        .collect();
+
+    if let Some(manifest) = manifest {
+        for (name, capabilities) in GRANTS {
+            let Some(import) = imports.iter().find(|import| import.name == *name) else {
+                continue;
+            };
+            if !capabilities.iter().any(|c| manifest.capabilities.iter().any(|m| m == c)) {
+                problems.push(Problem::NotGranted {
+                    import: import.clone(),
+                    capability: capabilities[0].to_string(),
+                });
+            }
+        }
+    }
+
+    for required in REQUIRED_EXPORTS {
+        if !exports.iter().any(|export| export.name == *required) {
+            problems.push(Problem::MissingExport(required.to_string()));
+        }
+    }
+
+    // The linker has the final say on types, but only report it when nothing more specific was found
+    if problems.is_empty()
+        && let Err(e) = linker.instantiate_pre(component)
+    {
+        problems.push(Problem::Incompatible(format!("{:#}", e)));
+    }
+
+    Report {
+        extension_id: extension_id.clone(),
+        imports,
+        exports,
+        problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provided_interfaces() {
+        assert!(Interface::parse("wasi:http/types@0.2.0").is_provided());
+        assert!(Interface::parse("wasi:io/streams@0.2.2").is_provided());
+        assert!(Interface::parse("emporium:extensions/types@0.1.0").is_provided());
+        assert!(Interface::parse("log").is_provided());
+        assert!(!Interface::parse("wasi:http/types@0.3.0").is_provided());
+        assert!(!Interface::parse("wasi:keyvalue/store@0.2.0").is_provided());
+        assert!(!Interface::parse("emporium:extensions/types").is_provided());
+    }
+}
@@ -1,11 +1,33 @@
+pub mod audit;
+pub mod batch;
+pub mod broadcast;
+pub mod bus;
+pub mod cache;
+pub mod config;
 pub mod data;
+pub mod dependency;
 pub mod error;
 pub mod extension;
+mod filesystem;
+pub mod health;
+pub mod http;
+pub mod inspect;
+pub mod lifecycle;
+pub mod metrics;
+pub mod middleware;
+pub mod permission;
+pub mod pipeline;
+pub mod redact;
 pub mod registry;
+pub mod schedule;
+pub mod signature;
+pub mod state;
+pub mod tenant;
+mod ttl;
 pub mod wasm;
 
 pub use data::{Command, Id, Response};
 pub use error::Error;
 pub use extension::{Manifest, list};
 pub use registry::Registry;
-pub use wasm::{Extension, load};
+pub use wasm::{Extension, load, load_verified};
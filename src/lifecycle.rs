@@ -0,0 +1,36 @@
+//! What happens to a registered extension, separate from the responses it sends.
+use crate::Error;
+use crate::data::Id;
+
+/// What an extension reports about itself when it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub id: Id,
+    pub name: String,
+    pub version: String,
+    pub description: String,
+}
+
+/// A change in the state of a registered extension, published on [`Registry::lifecycle`](crate::Registry::lifecycle)
+#[derive(Debug, Clone)]
+pub enum Lifecycle {
+    /// The component is being compiled and instantiated
+    Loading,
+    /// The extension is connected and accepting commands
+    Ready(Metadata),
+    /// The extension failed to start or stopped unexpectedly, and was removed from the registry
+    Crashed(Error),
+    /// The extension is being started again from the same component. [`Loading`](Self::Loading) follows.
+    Restarted,
+    /// The extension is being replaced by a new component. [`Loading`](Self::Loading) follows.
+    Reloaded,
+    /// The extension was removed from the registry
+    Unregistered,
+}
+
+impl Lifecycle {
+    /// Whether the extension is gone from the registry after this event
+    pub fn is_final(&self) -> bool {
+        matches!(self, Lifecycle::Crashed(_) | Lifecycle::Unregistered)
+    }
+}
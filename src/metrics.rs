@@ -0,0 +1,321 @@
+//! Counters and latency histograms per extension and per tool, see [`Registry::metrics`](crate::Registry::metrics).
+//!
+//! A command counts as a success unless the extension answers it with an error or traps on it. Fuel is the
+//! wasmtime fuel the guest burns while handling a command, which tracks the number of instructions it executes. It
+//! is only counted for extensions with [fuel metering](crate::Registry::with_fuel_metering) on, and is zero otherwise.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::data::Id;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// A distribution of command latencies
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations that fell in each of [`LATENCY_BUCKETS`], not including the ones before it
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    /// The sum of all observations, in seconds
+    pub sum: f64,
+}
+
+/// Commands sent to an extension, or to one of its tools
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calls {
+    pub commands: u64,
+    pub successes: u64,
+    pub errors: u64,
+    pub latency: Histogram,
+    pub fuel: u64,
+}
+
+/// Everything measured about one extension
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtensionMetrics {
+    /// All commands, including those for tools
+    pub calls: Calls,
+    /// `ExecuteTool` commands, by tool id
+    pub tools: BTreeMap<String, Calls>,
+    /// The most linear memory the extension has had at once, in bytes, across all of its instances
+    pub peak_memory: u64,
+    /// Outgoing HTTP requests
+    pub http_requests: u64,
+    /// Bytes received in outgoing HTTP response bodies
+    pub http_bytes: u64,
+    pub restarts: u64,
+}
+
+/// A snapshot of the metrics of every extension a registry has run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub extensions: BTreeMap<Id, ExtensionMetrics>,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+impl Calls {
+    fn record(&mut self, success: bool, latency: Duration, fuel: u64) {
+        self.commands += 1;
+        if success {
+            self.successes += 1;
+        } else {
+            self.errors += 1;
+        }
+        self.latency.observe(latency);
+        self.fuel += fuel;
+    }
+}
+
+impl Metrics {
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let extensions: Vec<(String, &Calls)> = self
+            .extensions
+            .iter()
+            .map(|(id, metrics)| (labels(&[("extension", id)]), &metrics.calls))
+            .collect();
+        let tools: Vec<(String, &Calls)> = self
+            .extensions
+            .iter()
+            .flat_map(|(id, metrics)| {
+                metrics
+                    .tools
+                    .iter()
+                    .map(move |(tool, calls)| (labels(&[("extension", id), ("tool", tool)]), calls))
+            })
+            .collect();
+        calls(&mut out, "emporium_extension", "extension", &extensions);
+        calls(&mut out, "emporium_tool", "tool", &tools);
+
+        let families: [Family<ExtensionMetrics>; 4] = [
+            (
+                "emporium_extension_peak_memory_bytes",
+                "gauge",
+                "The most linear memory the extension instance has had",
+                |m| m.peak_memory,
+            ),
+            (
+                "emporium_extension_http_requests_total",
+                "counter",
+                "Outgoing HTTP requests made by the extension",
+                |m| m.http_requests,
+            ),
+            (
+                "emporium_extension_http_response_bytes_total",
+                "counter",
+                "Bytes received in HTTP response bodies by the extension",
+                |m| m.http_bytes,
+            ),
+            (
+                "emporium_extension_restarts_total",
+                "counter",
+                "Times the extension was restarted",
+                |m| m.restarts,
+            ),
+        ];
+        for (name, kind, help, value) in families {
+            header(&mut out, name, kind, help);
+            for (id, metrics) in &self.extensions {
+                let _ = writeln!(out, "{}{} {}", name, labels(&[("extension", id)]), value(metrics));
+            }
+        }
+
+        out
+    }
+}
+
+/// Write the families for commands sent to extensions or tools, one sample per entry in `samples`
+fn calls(out: &mut String, prefix: &str, subject: &str, samples: &[(String, &Calls)]) {
+    let families: [Family<Calls>; 4] = [
+        ("commands_total", "counter", "Commands sent to the", |c| c.commands),
+        (
+            "successes_total",
+            "counter",
+            "Commands answered without an error by the",
+            |c| c.successes,
+        ),
+        (
+            "errors_total",
+            "counter",
+            "Commands answered with an error by the",
+            |c| c.errors,
+        ),
+        (
+            "fuel_consumed_total",
+            "counter",
+            "Fuel consumed handling commands by the",
+            |c| c.fuel,
+        ),
+    ];
+    for (suffix, kind, help, value) in families {
+        let name = format!("{}_{}", prefix, suffix);
+        header(out, &name, kind, &format!("{} {}", help, subject));
+        for (labels, calls) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value(calls));
+        }
+    }
+
+    let name = format!("{}_latency_seconds", prefix);
+    header(
+        out,
+        &name,
+        "histogram",
+        &format!("Time the {} took to answer commands", subject),
+    );
+    for (labels, calls) in samples {
+        // Sample labels are `{...}`, and buckets add `le` to them
+        let inner = &labels[1..labels.len() - 1];
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(calls.latency.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, inner, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, inner, calls.latency.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, calls.latency.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, calls.latency.count);
+    }
+}
+
+/// A metric family's name, type and help text, and how to read its value
+type Family<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// A label set such as `{extension="kv"}`
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Where an extension's runtime and the registry record its metrics
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Recorder {
+    metrics: Arc<Mutex<ExtensionMetrics>>,
+    /// Bytes of linear memory across the extension's live instances
+    memory: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    /// Record a command, and the tool it executed if any
+    pub(crate) fn command(&self, tool_id: Option<&str>, success: bool, latency: Duration, fuel: u64) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.calls.record(success, latency, fuel);
+        if let Some(tool_id) = tool_id {
+            metrics
+                .tools
+                .entry(tool_id.to_string())
+                .or_default()
+                .record(success, latency, fuel);
+        }
+    }
+
+    pub(crate) fn http(&self, bytes: u64) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.http_requests += 1;
+        metrics.http_bytes += bytes;
+    }
+
+    pub(crate) fn restart(&self) {
+        self.metrics.lock().unwrap().restarts += 1;
+    }
+
+    pub(crate) fn snapshot(&self) -> ExtensionMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+}
+
+/// Tracks how much linear memory an instance has, as a resource limiter that never refuses. The instances of an
+/// extension add theirs up in its recorder, and take it back when they are dropped.
+pub(crate) struct Memory {
+    recorder: Option<Recorder>,
+    /// Bytes across all of the instance's memories, which never shrink
+    total: usize,
+}
+
+impl Memory {
+    pub(crate) fn new(recorder: Option<Recorder>) -> Self {
+        Self { recorder, total: 0 }
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.memory.fetch_sub(self.total as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl wasmtime::ResourceLimiter for Memory {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        let grown = desired.saturating_sub(current);
+        self.total += grown;
+        if let Some(recorder) = &self.recorder {
+            let memory = recorder.memory.fetch_add(grown as u64, Ordering::Relaxed) + grown as u64;
+            let mut metrics = recorder.metrics.lock().unwrap();
+            metrics.peak_memory = metrics.peak_memory.max(memory);
+        }
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: usize, _desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus() {
+        let recorder = Recorder::default();
+        recorder.command(None, true, Duration::from_millis(2), 10);
+        recorder.command(Some("get"), false, Duration::from_secs(20), 5);
+        recorder.http(128);
+
+        let snapshot = recorder.snapshot();
+        assert_eq!(
+            (snapshot.calls.commands, snapshot.calls.errors, snapshot.calls.fuel),
+            (2, 1, 15)
+        );
+        assert_eq!(snapshot.tools["get"].commands, 1);
+        assert_eq!(snapshot.calls.latency.buckets[1], 1);
+
+        let metrics = Metrics {
+            extensions: BTreeMap::from([("k\"v".to_string(), snapshot)]),
+        };
+        let text = metrics.prometheus();
+        assert!(text.contains("# TYPE emporium_extension_commands_total counter\n"));
+        assert!(text.contains("emporium_extension_commands_total{extension=\"k\\\"v\"} 2\n"));
+        assert!(text.contains("emporium_tool_errors_total{extension=\"k\\\"v\",tool=\"get\"} 1\n"));
+        assert!(text.contains("emporium_extension_latency_seconds_bucket{extension=\"k\\\"v\",le=\"0.0025\"} 1\n"));
+        assert!(text.contains("emporium_extension_latency_seconds_bucket{extension=\"k\\\"v\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("emporium_extension_http_response_bytes_total{extension=\"k\\\"v\"} 128\n"));
+    }
+}
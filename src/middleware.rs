@@ -0,0 +1,84 @@
+//! Hooks around the commands a [`Registry`](crate::Registry) sends to extensions.
+//!
+//! Middleware sees every command sent to an extension: [`call`](crate::Registry::call),
+//! [`execute`](crate::Registry::execute), [`broadcast`](crate::Registry::broadcast), tool calls between extensions,
+//! [`send_message`](crate::Registry::send_message) and events from the [bus](crate::bus), whose caller is the
+//! publishing extension. Only the registry's own health probes and tool catalog requests bypass it. Each one gets the
+//! [`Call`] and the rest of the chain as [`Next`], so it can rewrite the command before passing it on, answer it
+//! itself without passing it on, or change the reply on the way back. The replies to messages and events are
+//! discarded or published once middleware is done with them. Middleware runs in the order it was added, the first
+//! one outermost.
+//!
+//! ```
+//! use emporium::middleware::{Call, Middleware, Next, Reply};
+//! use emporium::{Command, Response};
+//!
+//! /// Refuse tools that write
+//! struct ReadOnly;
+//!
+//! impl Middleware for ReadOnly {
+//!     fn handle<'a>(&'a self, call: Call, next: Next<'a>) -> Reply<'a> {
+//!         match &call.command {
+//!             Command::ExecuteTool { tool_id, .. } if tool_id.starts_with("set") => {
+//!                 Box::pin(async { Ok(Response::Error("Read only".to_string())) })
+//!             }
+//!             _ => next.run(call),
+//!         }
+//!     }
+//! }
+//! ```
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::Error;
+use crate::data::{Command, Id, Response};
+
+/// The eventual reply to a [`Call`]
+pub type Reply<'a> = Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + 'a>>;
+
+/// A command on its way to an extension
+#[derive(Debug, Clone)]
+pub struct Call {
+    /// The registry id of the extension the command is for
+    pub extension_id: Id,
+    pub command: Command,
+    /// The extension that sent the command, or `None` for the host
+    pub caller: Option<Id>,
+    /// Skip the [tool cache](crate::cache) and ask the extension, storing the fresh result
+    pub fresh: bool,
+}
+
+/// Runs around every command the registry sends, see the [module docs](self)
+pub trait Middleware: Send + Sync + 'static {
+    fn handle<'a>(&'a self, call: Call, next: Next<'a>) -> Reply<'a>;
+}
+
+/// The rest of the chain after a middleware, ending with the extension itself
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    send: &'a (dyn Fn(Call) -> Reply<'a> + Send + Sync),
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Arc<dyn Middleware>],
+        send: &'a (dyn Fn(Call) -> Reply<'a> + Send + Sync),
+    ) -> Self {
+        Self { middleware, send }
+    }
+
+    /// Pass `call` on to the next middleware, or to the extension if this is the last
+    pub fn run(self, call: Call) -> Reply<'a> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                call,
+                Next {
+                    middleware: rest,
+                    send: self.send,
+                },
+            ),
+            None => (self.send)(call),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Ask the host before an extension uses a sensitive capability.
+//!
+//! The runtime consults a [`Policy`] the first time an extension reaches for a [`Capability`]. Lasting decisions are
+//! remembered per extension id and version, and optionally persisted to a JSON file.
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use futures::channel::{mpsc, oneshot};
+use futures::future::Shared;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::data::Id;
+
+/// Something an extension can only do with the host's permission
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Capability {
+    /// An outgoing HTTP request to `authority` (e.g. `api.polygon.io`)
+    Http { authority: String },
+    /// Access to a directory on the host
+    Filesystem { path: PathBuf },
+    /// Looking up a host secret by name
+    Secret { name: String },
+    /// Executing a tool of another extension
+    Tool { extension_id: Id, tool_id: String },
+}
+
+/// The host's answer to a permission [`Request`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Allow this use only; the policy is asked again next time
+    AllowOnce,
+    /// Allow this and every later use by the same extension version
+    AllowAlways,
+    /// Deny this and every later use by the same extension version
+    Deny,
+}
+
+/// A capability an extension is about to use for the first time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub extension_id: Id,
+    pub version: String,
+    pub capability: Capability,
+}
+
+/// Decides whether an extension may use a capability.
+///
+/// Any `Fn(&Request) -> Decision` is a policy. Hosts that need to ask a user can use [`prompt`] instead.
+pub trait Policy: Send + Sync + 'static {
+    fn decide(&self, request: Request) -> Pin<Box<dyn Future<Output = Decision> + Send + '_>>;
+}
+
+impl<F> Policy for F
+where
+    F: Fn(&Request) -> Decision + Send + Sync + 'static,
+{
+    fn decide(&self, request: Request) -> Pin<Box<dyn Future<Output = Decision> + Send + '_>> {
+        Box::pin(std::future::ready(self(&request)))
+    }
+}
+
+/// A pending permission request waiting for an answer from the host application
+#[derive(Debug)]
+pub struct Prompt {
+    pub request: Request,
+    reply: oneshot::Sender<Decision>,
+}
+
+impl Prompt {
+    /// Answer the request. Dropping a prompt without answering denies it.
+    pub fn answer(self, decision: Decision) {
+        let _ = self.reply.send(decision);
+    }
+}
+
+struct Prompter(mpsc::UnboundedSender<Prompt>);
+
+impl Policy for Prompter {
+    fn decide(&self, request: Request) -> Pin<Box<dyn Future<Output = Decision> + Send + '_>> {
+        let (reply, answer) = oneshot::channel();
+        let sent = self.0.unbounded_send(Prompt { request, reply });
+
+        Box::pin(async move {
+            match sent {
+                Ok(()) => answer.await.unwrap_or(Decision::Deny),
+                Err(_) => Decision::Deny,
+            }
+        })
+    }
+}
+
+/// A policy that forwards every request to the returned receiver, e.g. to show a dialog in a GUI
+pub fn prompt() -> (impl Policy, mpsc::UnboundedReceiver<Prompt>) {
+    let (tx, rx) = mpsc::unbounded();
+    (Prompter(tx), rx)
+}
+
+/// A remembered decision
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Grant {
+    capability: Capability,
+    allow: bool,
+}
+
+/// A decision the policy is still making, which every check of the same capability waits for
+type Pending = Shared<Pin<Box<dyn Future<Output = Decision> + Send>>>;
+
+/// A [`Policy`] together with the decisions it has made so far
+#[derive(Clone)]
+pub struct Permissions {
+    policy: Arc<dyn Policy>,
+    grants: Arc<Mutex<HashMap<String, Vec<Grant>>>>,
+    /// Requests the policy is deciding, by extension version and capability
+    pending: Arc<Mutex<HashMap<(String, Capability), Pending>>>,
+    path: Option<PathBuf>,
+    /// Bumped with every change to `grants`, so an older snapshot never overwrites a newer one on disk
+    generation: Arc<AtomicU64>,
+    /// The generation last written to `path`
+    saved: Arc<Mutex<u64>>,
+}
+
+impl Permissions {
+    /// Create a permission store that only remembers decisions in memory
+    pub fn new(policy: impl Policy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            grants: Arc::default(),
+            pending: Arc::default(),
+            path: None,
+            generation: Arc::default(),
+            saved: Arc::default(),
+        }
+    }
+
+    /// Create a permission store backed by the JSON file at `path`, loading any decisions already saved there
+    pub fn load(policy: impl Policy, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let grants = if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            serde_json::from_str(&content)
+                .map_err(|e| Error::Custom(format!("Invalid permissions file {}: {}", path.display(), e)))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            policy: Arc::new(policy),
+            grants: Arc::new(Mutex::new(grants)),
+            pending: Arc::default(),
+            path: Some(path),
+            generation: Arc::default(),
+            saved: Arc::default(),
+        })
+    }
+
+    /// Whether the extension may use `capability`, asking the policy if it has not decided before.
+    ///
+    /// Concurrent checks of the same capability by the same extension version ask the policy once and share its
+    /// decision, even [`Decision::AllowOnce`].
+    pub async fn check(&self, extension_id: &str, version: &str, capability: Capability) -> bool {
+        let key = key(extension_id, version);
+
+        let decision = {
+            // Checked with `pending` locked: a decision is remembered before it stops being pending
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(allow) = self.remembered(&key, &capability) {
+                return allow;
+            }
+
+            pending
+                .entry((key.clone(), capability.clone()))
+                .or_insert_with(|| self.decide(key, extension_id, version, capability))
+                .clone()
+        };
+
+        matches!(decision.await, Decision::AllowOnce | Decision::AllowAlways)
+    }
+
+    fn remembered(&self, key: &str, capability: &Capability) -> Option<bool> {
+        self.grants.lock().unwrap().get(key).and_then(|grants| {
+            grants
+                .iter()
+                .find(|grant| grant.capability == *capability)
+                .map(|grant| grant.allow)
+        })
+    }
+
+    /// Ask the policy, then remember a lasting decision and stop waiting on it
+    fn decide(&self, key: String, extension_id: &str, version: &str, capability: Capability) -> Pending {
+        let request = Request {
+            extension_id: extension_id.to_string(),
+            version: version.to_string(),
+            capability: capability.clone(),
+        };
+        let permissions = self.clone();
+
+        let decide: Pin<Box<dyn Future<Output = Decision> + Send>> = Box::pin(async move {
+            let decision = permissions.policy.decide(request).await;
+            if decision != Decision::AllowOnce {
+                let allow = decision == Decision::AllowAlways;
+                permissions.remember(
+                    key.clone(),
+                    Grant {
+                        capability: capability.clone(),
+                        allow,
+                    },
+                );
+            }
+            permissions.pending.lock().unwrap().remove(&(key, capability));
+            decision
+        });
+        decide.shared()
+    }
+
+    /// Decisions remembered for an extension version
+    pub fn decisions(&self, extension_id: &str, version: &str) -> Vec<(Capability, bool)> {
+        self.grants
+            .lock()
+            .unwrap()
+            .get(&key(extension_id, version))
+            .map(|grants| grants.iter().map(|g| (g.capability.clone(), g.allow)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget every decision for an extension version so the policy is asked again
+    pub fn forget(&self, extension_id: &str, version: &str) {
+        self.update(|grants| {
+            grants.remove(&key(extension_id, version));
+        });
+    }
+
+    fn remember(&self, key: String, grant: Grant) {
+        self.update(|grants| {
+            let entry = grants.entry(key).or_default();
+            entry.retain(|g| g.capability != grant.capability);
+            entry.push(grant);
+        });
+    }
+
+    /// Change the decisions, then save them without holding the lock on them
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, Vec<Grant>>)) {
+        let (generation, json) = {
+            let mut grants = self.grants.lock().unwrap();
+            f(&mut grants);
+            let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+            (
+                generation,
+                self.path.as_ref().map(|_| serde_json::to_string_pretty(&*grants)),
+            )
+        };
+
+        if let (Some(path), Some(json)) = (&self.path, json) {
+            self.save(path, generation, json);
+        }
+    }
+
+    fn save(&self, path: &Path, generation: u64, json: serde_json::Result<String>) {
+        let mut saved = self.saved.lock().unwrap();
+        if generation < *saved {
+            return;
+        }
+
+        match json
+            .map_err(std::io::Error::other)
+            .and_then(|json| std::fs::write(path, json))
+        {
+            Ok(()) => *saved = generation,
+            Err(e) => eprintln!("Failed to save permissions to {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::fmt::Debug for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Permissions").field("path", &self.path).finish()
+    }
+}
+
+fn key(extension_id: &str, version: &str) -> String {
+    format!("{extension_id}@{version}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_decisions_are_remembered_per_version() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let permissions = Permissions::new(move |request: &Request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match &request.capability {
+                Capability::Http { authority } if authority == "api.polygon.io" => Decision::AllowAlways,
+                Capability::Secret { .. } => Decision::AllowOnce,
+                _ => Decision::Deny,
+            }
+        });
+        let http = |authority: &str| Capability::Http {
+            authority: authority.to_string(),
+        };
+
+        assert!(permissions.check("polygon", "0.1.0", http("api.polygon.io")).await);
+        assert!(permissions.check("polygon", "0.1.0", http("api.polygon.io")).await);
+        assert!(!permissions.check("polygon", "0.1.0", http("example.com")).await);
+        assert!(!permissions.check("polygon", "0.1.0", http("example.com")).await);
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+
+        let secret = Capability::Secret {
+            name: "api_key".to_string(),
+        };
+        assert!(permissions.check("polygon", "0.1.0", secret.clone()).await);
+        assert!(permissions.check("polygon", "0.1.0", secret).await);
+        assert_eq!(asked.load(Ordering::SeqCst), 4);
+
+        assert!(permissions.check("polygon", "0.2.0", http("api.polygon.io")).await);
+        assert_eq!(asked.load(Ordering::SeqCst), 5);
+        assert_eq!(permissions.decisions("polygon", "0.1.0").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_checks_prompt_once() {
+        use futures::StreamExt;
+
+        let path = std::env::temp_dir().join(format!("emporium-permissions-{}.json", std::process::id()));
+        let (policy, mut prompts) = prompt();
+        let permissions = Permissions::load(policy, &path).unwrap();
+        let secret = || Capability::Secret {
+            name: "api_key".to_string(),
+        };
+
+        let checks = futures::future::join(
+            permissions.check("polygon", "0.1.0", secret()),
+            permissions.check("polygon", "0.1.0", secret()),
+        );
+        let answer = async {
+            let prompt = prompts.next().await.unwrap();
+            prompt.answer(Decision::AllowAlways);
+        };
+        let ((first, second), ()) = futures::future::join(checks, answer).await;
+
+        assert!(first && second);
+        assert!(prompts.try_recv().is_err(), "The policy was asked twice");
+        let saved: HashMap<String, Vec<Grant>> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved["polygon@0.1.0"].len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+//! Chains of tool calls across extensions, declared in TOML and run by
+//! [`Registry::run_pipeline`](crate::Registry::run_pipeline).
+//!
+//! ```toml
+//! name = "close"
+//!
+//! [[steps]]
+//! id = "bars"
+//! tool = "polygon.aggregates"
+//! params = { ticker = "$input.ticker", timespan = "day" }
+//! retries = 2
+//!
+//! # A step without a tool just builds its result from its params
+//! [[steps]]
+//! id = "summary"
+//! params = { ticker = "$input.ticker", closes = "$bars.results[*].c" }
+//!
+//! [[steps]]
+//! id = "store"
+//! tool = "kv.set"
+//! params = { key = "close", value = "$" }
+//! on_error = "continue"
+//! ```
+//!
+//! Any string in `params` that starts with `$` is replaced by the value it points to: `$input` is the input the
+//! pipeline runs with, `$<id>` the result of an earlier step and a bare `$` the result of the step before, or the
+//! input for the first step. Paths continue with `.field`, `[index]` and `[*]`, which maps the rest of the path over
+//! every item of an array. Write `$$` for a string that starts with a literal `$`.
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{Error, Response};
+
+/// The name that refers to the pipeline's input in references
+const INPUT: &str = "input";
+
+/// A sequence of steps, each executing a tool or building a value
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// How later steps refer to its result
+    pub id: String,
+    /// The qualified id of the tool to execute, or `None` to use the mapped params as the result
+    pub tool: Option<String>,
+    #[serde(default)]
+    pub params: Value,
+    /// How many times to try again after the tool fails
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+/// What a failed step does to the rest of the pipeline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Stop the pipeline
+    #[default]
+    Fail,
+    /// Go on with the next step, with `null` as the failed step's result
+    Continue,
+}
+
+/// What happened when a pipeline ran
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The steps that ran, in order
+    pub steps: Vec<StepTrace>,
+    /// The result of the last step, or `None` if a failed step stopped the pipeline
+    pub output: Option<Value>,
+}
+
+/// What happened in one step
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepTrace {
+    pub id: String,
+    pub tool: Option<String>,
+    /// The params after mapping, or `null` if they could not be mapped
+    pub params: Value,
+    pub result: Result<Value, String>,
+    /// How many times the tool was executed
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+impl Pipeline {
+    /// Parse and check a pipeline definition
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        let pipeline: Self =
+            toml::from_str(toml).map_err(|e| Error::PipelineError(e.to_string().trim_end().to_string()))?;
+        pipeline.check()?;
+        Ok(pipeline)
+    }
+
+    /// Read and parse the pipeline definition at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+
+        Self::parse(&content).map_err(|e| match e {
+            Error::PipelineError(message) => Error::PipelineError(format!("{}: {}", path.display(), message)),
+            e => e,
+        })
+    }
+
+    /// Make sure step ids are usable and every reference points to the input or an earlier step
+    fn check(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::PipelineError(format!("Pipeline {}: {}", self.name, message)));
+
+        if self.steps.is_empty() {
+            return invalid("has no steps".to_string());
+        }
+
+        let mut earlier: Vec<&str> = Vec::new();
+        for step in &self.steps {
+            if step.id.is_empty() || step.id == INPUT || !step.id.chars().all(is_name) {
+                return invalid(format!("invalid step id {:?}", step.id));
+            }
+            if earlier.contains(&step.id.as_str()) {
+                return invalid(format!("duplicate step id {:?}", step.id));
+            }
+
+            let mut references = Vec::new();
+            collect(&step.params, &mut references);
+            for text in references {
+                let reference = Reference::parse(text)
+                    .map_err(|e| Error::PipelineError(format!("Pipeline {}, step {}: {}", self.name, step.id, e)))?;
+                if let Root::Step(id) = reference.root
+                    && !earlier.contains(&id)
+                {
+                    return invalid(format!(
+                        "step {} refers to {:?}, which is not an earlier step",
+                        step.id, id
+                    ));
+                }
+            }
+
+            earlier.push(&step.id);
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `pipeline` on `input`, executing tools with `execute`
+pub(crate) async fn run<F, Fut>(pipeline: &Pipeline, input: Value, execute: F) -> Trace
+where
+    F: Fn(String, Value) -> Fut,
+    Fut: Future<Output = Result<Response, Error>>,
+{
+    let mut results: Vec<(&str, Value)> = Vec::new();
+    let mut trace = Trace {
+        steps: Vec::new(),
+        output: None,
+    };
+
+    for step in &pipeline.steps {
+        let started = Instant::now();
+        let previous = results.last().map_or(&input, |(_, result)| result);
+        let context = Context {
+            input: &input,
+            previous,
+            results: &results,
+        };
+
+        let mut attempts = 0;
+        let (params, result) = match map(&step.params, &context) {
+            Err(e) => (Value::Null, Err(e)),
+            Ok(params) => match &step.tool {
+                None => (params.clone(), Ok(params)),
+                Some(tool) => loop {
+                    attempts += 1;
+                    let result = match execute(tool.clone(), params.clone()).await {
+                        Ok(Response::ToolResult { result, .. }) => Ok(result),
+                        Ok(Response::Error(e)) => Err(e),
+                        Ok(response) => Err(format!("Unexpected response {:?}", response)),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    if result.is_ok() || attempts > step.retries {
+                        break (params, result);
+                    }
+                },
+            },
+        };
+
+        let stop = result.is_err() && step.on_error == OnError::Fail;
+        results.push((&step.id, result.clone().unwrap_or_default()));
+        trace.steps.push(StepTrace {
+            id: step.id.clone(),
+            tool: step.tool.clone(),
+            params,
+            result,
+            attempts,
+            elapsed: started.elapsed(),
+        });
+
+        if stop {
+            return trace;
+        }
+    }
+
+    trace.output = results.pop().map(|(_, result)| result);
+    trace
+}
+
+/// What references can point to while a step runs
+struct Context<'a> {
+    input: &'a Value,
+    previous: &'a Value,
+    results: &'a [(&'a str, Value)],
+}
+
+/// Replace every reference in `params` by the value it points to
+fn map(params: &Value, context: &Context) -> Result<Value, String> {
+    Ok(match params {
+        Value::String(text) if text.starts_with("$$") => Value::String(text[1..].to_string()),
+        Value::String(text) if text.starts_with('$') => {
+            let reference = Reference::parse(text)?;
+            let root = match reference.root {
+                Root::Previous => context.previous,
+                Root::Input => context.input,
+                Root::Step(id) => context
+                    .results
+                    .iter()
+                    .find(|(step, _)| *step == id)
+                    .map(|(_, result)| result)
+                    .ok_or_else(|| format!("{} refers to a step that has not run", text))?,
+            };
+            select(root, &reference.path).ok_or_else(|| format!("{} matches nothing", text))?
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| map(item, context)).collect::<Result<_, _>>()?),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), map(value, context)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        value => value.clone(),
+    })
+}
+
+/// Every reference in `params`
+fn collect<'a>(params: &'a Value, references: &mut Vec<&'a str>) {
+    match params {
+        Value::String(text) if text.starts_with('$') && !text.starts_with("$$") => references.push(text),
+        Value::Array(items) => items.iter().for_each(|item| collect(item, references)),
+        Value::Object(fields) => fields.values().for_each(|value| collect(value, references)),
+        _ => {}
+    }
+}
+
+/// Follow `path` from `value`
+fn select(value: &Value, path: &[Segment]) -> Option<Value> {
+    let Some((first, rest)) = path.split_first() else {
+        return Some(value.clone());
+    };
+
+    match first {
+        Segment::Field(name) => select(value.get(name)?, rest),
+        Segment::Index(index) => select(value.get(index)?, rest),
+        Segment::All => value
+            .as_array()?
+            .iter()
+            .map(|item| select(item, rest))
+            .collect::<Option<_>>()
+            .map(Value::Array),
+    }
+}
+
+/// A parsed `$` reference
+#[derive(Debug, PartialEq)]
+struct Reference<'a> {
+    root: Root<'a>,
+    path: Vec<Segment<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Root<'a> {
+    Previous,
+    Input,
+    Step(&'a str),
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+    Field(&'a str),
+    Index(usize),
+    All,
+}
+
+impl<'a> Reference<'a> {
+    fn parse(text: &'a str) -> Result<Self, String> {
+        let invalid = || format!("Invalid reference {:?}", text);
+        let rest = text.strip_prefix('$').ok_or_else(invalid)?;
+
+        let (name, mut rest) = rest.split_at(rest.find(|c| !is_name(c)).unwrap_or(rest.len()));
+        let root = match name {
+            "" => Root::Previous,
+            INPUT => Root::Input,
+            name => Root::Step(name),
+        };
+
+        let mut path = Vec::new();
+        while !rest.is_empty() {
+            if let Some(field) = rest.strip_prefix('.') {
+                let end = field.find(['.', '[']).unwrap_or(field.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                path.push(Segment::Field(&field[..end]));
+                rest = &field[end..];
+            } else if let Some(index) = rest.strip_prefix('[') {
+                let (index, after) = index.split_once(']').ok_or_else(invalid)?;
+                path.push(match index {
+                    "*" => Segment::All,
+                    index => Segment::Index(index.parse().map_err(|_| invalid())?),
+                });
+                rest = after;
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        Ok(Self { root, path })
+    }
+}
+
+fn is_name(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_pipeline_maps_results_between_steps() {
+        let pipeline = Pipeline::parse(
+            r#"
+            name = "close"
+
+            [[steps]]
+            id = "bars"
+            tool = "polygon.aggregates"
+            params = { ticker = "$input.ticker" }
+            retries = 1
+
+            [[steps]]
+            id = "summary"
+            params = { ticker = "$input.ticker", closes = "$bars.results[*].c", first = "$.results[0].c", note = "$$1" }
+
+            [[steps]]
+            id = "store"
+            tool = "kv.set"
+            params = { key = "close", value = "$" }
+            on_error = "continue"
+
+            [[steps]]
+            id = "done"
+            params = "$summary.closes[1]"
+            "#,
+        )
+        .unwrap();
+
+        // Polygon fails once before answering, and KV always fails
+        let calls = Mutex::new(Vec::new());
+        let execute = |tool: String, params: Value| {
+            let attempt = {
+                let mut calls = calls.lock().unwrap();
+                calls.push((tool.clone(), params));
+                calls.len()
+            };
+            async move {
+                match tool.as_str() {
+                    "polygon.aggregates" if attempt == 1 => Err(Error::Timeout("polygon".to_string())),
+                    "polygon.aggregates" => Ok(Response::ToolResult {
+                        tool_id: tool,
+                        result: json!({ "results": [{ "c": 1.5 }, { "c": 2.5 }] }),
+                    }),
+                    _ => Ok(Response::Error("Read only".to_string())),
+                }
+            }
+        };
+
+        let trace = run(&pipeline, json!({ "ticker": "AAPL" }), execute).await;
+        let summary = json!({ "ticker": "AAPL", "closes": [1.5, 2.5], "first": 1.5, "note": "$1" });
+
+        assert_eq!(trace.output, Some(json!(2.5)));
+        assert_eq!(trace.steps.len(), 4);
+        assert_eq!(trace.steps[0].attempts, 2);
+        assert_eq!(trace.steps[1].result, Ok(summary.clone()));
+        assert_eq!(trace.steps[2].params, json!({ "key": "close", "value": summary }));
+        assert_eq!(trace.steps[2].result, Err("Read only".to_string()));
+        assert_eq!(calls.lock().unwrap()[0].1, json!({ "ticker": "AAPL" }));
+
+        // A missing path fails the step, which stops the pipeline
+        let trace = run(&pipeline, json!({}), execute).await;
+        assert_eq!(trace.output, None);
+        assert_eq!(trace.steps.len(), 1);
+        assert!(trace.steps[0].result.as_ref().unwrap_err().contains("$input.ticker"));
+
+        for invalid in [
+            "name = 'a'\nsteps = []",
+            "name = 'a'\n[[steps]]\nid = 'x'\nparams = '$later'\n[[steps]]\nid = 'later'",
+            "name = 'a'\n[[steps]]\nid = 'x'\n[[steps]]\nid = 'x'",
+            "name = 'a'\n[[steps]]\nid = 'x'\nparams = '$.a[b]'",
+        ] {
+            assert!(
+                matches!(Pipeline::parse(invalid), Err(Error::PipelineError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
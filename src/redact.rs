@@ -0,0 +1,276 @@
+//! Keep secrets from leaving the runtime.
+//!
+//! A [`Redactor`] knows the values of an extension's secrets and sensitive config fields and replaces them in
+//! guest logs, captured stdio, error strings and audit records.
+//!
+//! Only whole values are matched, as is or percent-encoded. Part of a secret, such as a key prefix an extension
+//! logs for debugging, cannot be told apart from other text and is out of scope: extensions must not log it.
+use std::io::Write;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde_json::Value;
+use wasmtime_wasi::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
+
+use crate::audit::Event;
+
+/// What a redacted value is replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Values shorter than this are not redacted, since replacing them would mangle unrelated text
+const MIN_LEN: usize = 4;
+
+/// Replaces known secret values in text
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    values: Arc<Vec<String>>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redact `value` wherever it appears
+    pub fn with_value(self, value: impl Into<String>) -> Self {
+        self.with_values([value.into()])
+    }
+
+    /// Redact each of `values` wherever it appears, as is or percent-encoded as in a URL
+    pub fn with_values(self, values: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut all = Arc::unwrap_or_clone(self.values);
+        for value in values.into_iter().map(Into::into).filter(|v| v.len() >= MIN_LEN) {
+            // Either case of hex digits is valid in an escape
+            all.push(percent_encode(&value, false));
+            all.push(percent_encode(&value, true));
+            all.push(value);
+        }
+
+        // Longest first, so a secret containing another is replaced whole
+        all.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        all.dedup();
+
+        Self { values: Arc::new(all) }
+    }
+
+    /// Redact the string values of `fields` in a JSON config object
+    pub fn with_config(self, config: &Value, fields: &[String]) -> Self {
+        let values = fields.iter().filter_map(|field| config.get(field)?.as_str());
+        self.with_values(values)
+    }
+
+    /// Whether there is nothing to redact
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Replace every known value in `text`
+    pub fn redact(&self, text: &str) -> String {
+        self.values
+            .iter()
+            .fold(text.to_string(), |text, value| text.replace(value.as_str(), REDACTED))
+    }
+
+    /// Redact every string an audit event carries
+    pub fn event(&self, event: Event) -> Event {
+        if self.is_empty() {
+            return event;
+        }
+
+        match event {
+            Event::Http {
+                method,
+                url,
+                status,
+                bytes,
+                error,
+            } => Event::Http {
+                method,
+                url: self.redact(&url),
+                status,
+                bytes,
+                error: error.map(|e| self.redact(&e)),
+            },
+            Event::Log { level, message } => Event::Log {
+                level,
+                message: self.redact(&message),
+            },
+            Event::Command { command } => Event::Command {
+                command: self.redact(&command),
+            },
+            Event::Tool {
+                target,
+                tool_id,
+                granted,
+                error,
+            } => Event::Tool {
+                target,
+                tool_id,
+                granted,
+                error: error.map(|e| self.redact(&e)),
+            },
+            Event::Open { path, mode, error } => Event::Open {
+                path: self.redact(&path),
+                mode,
+                error: error.map(|e| self.redact(&e)),
+            },
+            event @ (Event::Secret { .. } | Event::Filesystem { .. } | Event::Publish { .. }) => event,
+        }
+    }
+}
+
+/// Config fields a JSON schema marks as sensitive.
+///
+/// A property is sensitive if it has `"secret": true`, `"writeOnly": true` or `"format": "password"`.
+pub fn sensitive_fields(schema: &Value) -> Vec<String> {
+    let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+        return Vec::new();
+    };
+
+    properties
+        .iter()
+        .filter(|(_, property)| {
+            property.get("secret") == Some(&Value::Bool(true))
+                || property.get("writeOnly") == Some(&Value::Bool(true))
+                || property.get("format").and_then(Value::as_str) == Some("password")
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// `value` with everything but unreserved URL characters percent-encoded
+fn percent_encode(value: &str, uppercase: bool) -> String {
+    value
+        .bytes()
+        .fold(String::with_capacity(value.len()), |mut encoded, byte| {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+                byte if uppercase => encoded.push_str(&format!("%{:02X}", byte)),
+                byte => encoded.push_str(&format!("%{:02x}", byte)),
+            }
+            encoded
+        })
+}
+
+/// Which host stream guest output is forwarded to
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stdio {
+    Stdout,
+    Stderr,
+}
+
+/// Guest stdout or stderr, redacted line by line before it reaches the host's
+pub(crate) struct RedactedStdio {
+    pub stdio: Stdio,
+    pub redactor: Redactor,
+}
+
+impl StdoutStream for RedactedStdio {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(RedactedOutput {
+            stdio: self.stdio,
+            redactor: self.redactor.clone(),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+/// Buffers output until a full line is written, so a secret split across writes is still caught
+struct RedactedOutput {
+    stdio: Stdio,
+    redactor: Redactor,
+    buffer: Vec<u8>,
+}
+
+impl RedactedOutput {
+    fn emit(&mut self, until: usize) {
+        let text = String::from_utf8_lossy(&self.buffer[..until]);
+        let redacted = self.redactor.redact(&text);
+
+        let _ = match self.stdio {
+            Stdio::Stdout => std::io::stdout().write_all(redacted.as_bytes()),
+            Stdio::Stderr => std::io::stderr().write_all(redacted.as_bytes()),
+        };
+        self.buffer.drain(..until);
+    }
+}
+
+impl HostOutputStream for RedactedOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.buffer.extend_from_slice(&bytes);
+
+        if let Some(last_newline) = self.buffer.iter().rposition(|b| *b == b'\n') {
+            self.emit(last_newline + 1);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        let _ = match self.stdio {
+            Stdio::Stdout => std::io::stdout().flush(),
+            Stdio::Stderr => std::io::stderr().flush(),
+        };
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(1024 * 1024)
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Subscribe for RedactedOutput {
+    async fn ready(&mut self) {}
+}
+
+impl Drop for RedactedOutput {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.emit(self.buffer.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_sensitive_config() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "api_key": { "type": "string", "secret": true },
+                "base_url": { "type": "string" }
+            }
+        });
+        let config = json!({ "api_key": "sk_live_1234", "base_url": "https://api.polygon.io" });
+
+        let fields = sensitive_fields(&schema);
+        assert_eq!(fields, vec!["api_key".to_string()]);
+
+        let redactor = Redactor::new().with_config(&config, &fields).with_value("abc");
+        assert_eq!(
+            redactor.redact("GET https://api.polygon.io/v3?apiKey=sk_live_1234 failed"),
+            "GET https://api.polygon.io/v3?apiKey=[REDACTED] failed"
+        );
+        // Secrets in a query string are percent-encoded
+        let redactor = redactor.with_value("p@ss/word");
+        assert_eq!(
+            redactor.redact("GET /v3?apiKey=p%40ss%2Fword&b=p%40ss%2fword"),
+            "GET /v3?apiKey=[REDACTED]&b=[REDACTED]"
+        );
+        // Too short to redact safely
+        assert_eq!(redactor.redact("abc"), "abc");
+        // Part of a secret is not recognised
+        assert_eq!(
+            redactor.redact("Initialized client using API key: sk_..."),
+            "Initialized client using API key: sk_..."
+        );
+    }
+}
@@ -1,92 +1,2578 @@
 //! Manage extensions and send them messages.
+use crate::batch::{self, Batch};
+use crate::broadcast::Broadcast;
+use crate::bus::{self, Message};
+use crate::cache::Cache;
+use crate::config::{ExtensionConfig, Failure, HostConfig, LoadReport, Loaded};
+use crate::data::ToolInfo;
+use crate::dependency;
+use crate::extension::{self, Entry};
+use crate::health::{Health, HealthCheck, Status};
+use crate::lifecycle::{Lifecycle, Metadata};
+use crate::metrics::{Metrics, Recorder};
+use crate::middleware::{Call, Middleware, Next, Reply};
+use crate::pipeline::{self, Pipeline, Trace};
+use crate::schedule::{self, Schedule, ScheduleId};
+use crate::signature::Trust;
+use crate::state::{Saved, State};
+use crate::tenant::{self, Tenant};
 use crate::{Command, Error, Extension, Id, Response};
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
+use futures::task::AtomicWaker;
 use futures::{Stream, StreamExt};
-use std::collections::HashMap;
+use sipper::Sipper;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
 
-pub struct Registry {
+/// Separates the extension id from the tool id in a qualified tool id, e.g. `polygon.call_endpoint`
+pub const NAMESPACE_SEPARATOR: char = '.';
+
+/// How long an extension waits on a tool of another extension by default
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A command on its way to an extension, and where to deliver its response
+struct Request {
+    command: Command,
+    reply: Pending,
+}
+
+/// A registered extension
+struct Handle {
+    /// Tells this registration apart from a later one under the same id
+    token: u64,
+    requests: mpsc::UnboundedSender<Request>,
+    controls: mpsc::UnboundedSender<Control>,
+    /// Capabilities enabled in the extension's manifest, if it has one
+    capabilities: Vec<String>,
+    /// Topic patterns the extension subscribes to
+    subscriptions: Vec<String>,
+    /// How to load the extension again
+    saved: Saved,
+    /// The task driving the extension, which finishes once the senders above are dropped
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Tells the task driving an extension to start it over
+enum Control {
+    /// From the same component
+    Restart,
+    /// From a new component
+    Reload(Box<Extension>),
+}
+
+/// Where the response to a command sent to an extension goes
+enum Pending {
+    /// To a caller waiting on [`Registry::call`]
+    Reply(oneshot::Sender<Result<Response, Error>>),
+    /// To a health probe, which does not count as a command in flight
+    Probe(oneshot::Sender<Result<Response, Error>>),
+    /// Into the tool catalog
+    Catalog,
+}
+
+/// Where the response to a command sent without waiting for it goes
+#[derive(Clone, Copy)]
+enum Post {
+    /// To [`Registry::events`]
+    Event,
+    /// Nowhere, e.g. the acknowledgement of an event delivery
+    Discard,
+}
+
+/// State shared between the registry and the tasks driving its extensions
+struct Shared {
     /// Loaded extensions
-    extensions: HashMap<Id, mpsc::UnboundedSender<Command>>,
+    extensions: Mutex<HashMap<Id, Handle>>,
+    /// Tools each extension provides, by extension id
+    tools: Mutex<HashMap<Id, Vec<ToolInfo>>>,
+    /// Health of each ready extension, by extension id
+    health: Mutex<HashMap<Id, Status>>,
+    /// The extensions each extension is waiting on in tool calls, with how many of its calls wait on each, to catch
+    /// calls that would deadlock
+    waiting: Mutex<HashMap<Id, HashMap<Id, usize>>>,
+    /// Topic patterns the host subscribes to, and where to deliver matching messages
+    subscribers: Mutex<Vec<(String, mpsc::UnboundedSender<Message>)>>,
+    /// Metrics of every extension registered so far, kept after it is gone
+    metrics: Mutex<HashMap<Id, Recorder>>,
+    /// Runs around every command sent to an extension, outermost first
+    middleware: Mutex<Vec<Arc<dyn Middleware>>>,
+    /// Where results of cacheable tools are kept
+    cache: Mutex<Option<Cache>>,
+    /// Quota and usage of each tenant, by name
+    tenants: Mutex<HashMap<String, Arc<tenant::Usage>>>,
     /// Event sender that extensions use
     event_tx: mpsc::UnboundedSender<(Id, Response)>,
+    /// Lifecycle sender that extensions use
+    lifecycle_tx: mpsc::UnboundedSender<(Id, Lifecycle)>,
+}
+
+pub struct Registry {
+    shared: Arc<Shared>,
+    /// Source of registration tokens
+    next_token: AtomicU64,
+    /// How long extensions wait on each other's tools
+    call_timeout: Duration,
+    /// Whether the fuel extensions burn is metered
+    fuel: bool,
+    /// Running schedules, which stop when they are dropped
+    schedules: Mutex<BTreeMap<ScheduleId, schedule::Control>>,
+    /// Source of schedule ids
+    next_schedule: AtomicU64,
     /// Event receiver that extensions use
     event_rx: mpsc::UnboundedReceiver<(Id, Response)>,
+    /// Lifecycle receiver that extensions use
+    lifecycle_rx: mpsc::UnboundedReceiver<(Id, Lifecycle)>,
 }
 
 impl Registry {
     /// Create a new registry
     pub fn new() -> Self {
         let (event_tx, event_rx) = mpsc::unbounded();
+        let (lifecycle_tx, lifecycle_rx) = mpsc::unbounded();
         Self {
-            extensions: HashMap::new(),
-            event_tx,
+            shared: Arc::new(Shared {
+                extensions: Mutex::default(),
+                tools: Mutex::default(),
+                health: Mutex::default(),
+                waiting: Mutex::default(),
+                subscribers: Mutex::default(),
+                metrics: Mutex::default(),
+                middleware: Mutex::default(),
+                cache: Mutex::default(),
+                tenants: Mutex::default(),
+                event_tx,
+                lifecycle_tx,
+            }),
+            next_token: AtomicU64::new(0),
+            call_timeout: DEFAULT_CALL_TIMEOUT,
+            fuel: false,
+            schedules: Mutex::default(),
+            next_schedule: AtomicU64::new(0),
             event_rx,
+            lifecycle_rx,
+        }
+    }
+
+    /// Give up on a tool call from one extension to another after `timeout`
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = timeout;
+        self
+    }
+
+    /// Meter the fuel every extension registered from now on burns, see [`Extension::with_fuel_metering`]
+    pub fn with_fuel_metering(mut self) -> Self {
+        self.fuel = true;
+        self
+    }
+
+    /// Run `middleware` around every command sent to an extension, after any middleware added before.
+    ///
+    /// See [`middleware`](crate::middleware) for what it can do.
+    pub fn with_middleware(self, middleware: impl Middleware) -> Self {
+        self.shared.middleware.lock().unwrap().push(Arc::new(middleware));
+        self
+    }
+
+    /// Serve results of tools that declare themselves cacheable from `cache` while they are fresh.
+    ///
+    /// Middleware still sees every call; the cache sits between it and the extension. An extension's results are
+    /// purged when it is reloaded or unregistered.
+    pub fn with_tool_cache(self, cache: Cache) -> Self {
+        *self.shared.cache.lock().unwrap() = Some(cache);
+        self
+    }
+
+    /// Probe every ready extension periodically, as configured by `check`.
+    ///
+    /// The results are available from [`health`](Self::health). Must be called within a Tokio runtime.
+    pub fn with_health_checks(self, check: HealthCheck) -> Self {
+        tokio::spawn(monitor(Arc::downgrade(&self.shared), check));
+        self
+    }
+
+    /// The health of an extension, or `None` if it is not registered or not ready yet
+    pub fn health(&self, extension_id: &Id) -> Option<Status> {
+        self.shared.health.lock().unwrap().get(extension_id).cloned()
+    }
+
+    /// The health of every ready extension
+    pub fn health_all(&self) -> HashMap<Id, Status> {
+        self.shared.health.lock().unwrap().clone()
+    }
+
+    /// Counters and latency histograms of every extension registered so far.
+    ///
+    /// Extensions keep their metrics after they are unregistered or crash, and pick them up again if they are
+    /// registered under the same id. Render them for Prometheus with [`Metrics::prometheus`].
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            extensions: self
+                .shared
+                .metrics
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, recorder)| (id.clone(), recorder.snapshot()))
+                .collect(),
+        }
+    }
+
+    /// Create a registry with the extensions found in `dirs`, configured by the host config file at `config`.
+    ///
+    /// Components are verified against the publishers the config's `[trust]` table names, see
+    /// [`load_all`](Self::load_all). Only an unreadable or invalid config file is an error; extensions that fail to
+    /// load are listed in the report.
+    pub async fn from_config(
+        config: impl AsRef<Path>,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Result<(Self, LoadReport), Error> {
+        let host = HostConfig::load(config)?;
+        let trust = host.trust()?;
+        let registry = Self::new();
+        let report = registry.load_all(&host, &trust, dirs).await;
+
+        Ok((registry, report))
+    }
+
+    /// Load and register every enabled extension found in `dirs`.
+    ///
+    /// When an id is found more than once, the first manifest matching the configured version wins, so earlier
+    /// directories take precedence. Extensions are loaded after the extensions they depend on, and refused if their
+    /// [dependencies](crate::dependency) cannot be met. Extensions the config names but no directory provides are
+    /// reported as failed, and so are components `trust` refuses.
+    pub async fn load_all(
+        &self,
+        host: &HostConfig,
+        trust: &Trust,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> LoadReport {
+        let mut report = LoadReport::default();
+        let mut found: Vec<Entry> = Vec::new();
+
+        for dir in dirs {
+            let dir = dir.as_ref().to_path_buf();
+            let mut list = crate::list(dir.clone()).pin();
+
+            while let Some(entry) = list.next().await {
+                found.push(entry);
+            }
+            if let Err(error) = list.await {
+                report.failed.push(Failure {
+                    id: None,
+                    path: Some(dir),
+                    error,
+                });
+            }
+        }
+
+        let mut ids: Vec<Id> = Vec::new();
+        for (_, manifest) in &found {
+            if !ids.contains(&manifest.id) {
+                ids.push(manifest.id.clone());
+            }
+        }
+
+        let mut selected = Vec::new();
+        for id in &ids {
+            let settings = host.extension(id);
+            if !settings.enabled {
+                report.disabled.push(id.clone());
+                continue;
+            }
+
+            let entry = found.iter().find(|(_, manifest)| {
+                manifest.id == *id && settings.version.as_ref().is_none_or(|v| *v == manifest.version)
+            });
+            match entry {
+                Some((path, manifest)) => selected.push((path, manifest, settings)),
+                None => report.failed.push(Failure {
+                    id: Some(id.clone()),
+                    path: None,
+                    error: Error::ExtensionNotFound(format!(
+                        "{} v{}",
+                        id,
+                        settings.version.as_deref().unwrap_or_default()
+                    )),
+                }),
+            }
+        }
+
+        let manifests: Vec<&crate::Manifest> = selected.iter().map(|(_, manifest, _)| *manifest).collect();
+        let (ordered, refused) = dependency::order(&manifests, &self.versions(None));
+
+        for (id, error) in refused {
+            let path = selected
+                .iter()
+                .find(|(_, m, _)| m.id == id)
+                .map(|(path, _, _)| path.to_path_buf());
+            report.failed.push(Failure {
+                id: Some(id),
+                path,
+                error: error.into(),
+            });
+        }
+
+        for manifest in ordered {
+            let (path, _, settings) = selected.iter().find(|(_, m, _)| m.id == manifest.id).unwrap();
+
+            match self
+                .load_entry(&manifest.id, path, Some(manifest), settings, trust, None)
+                .await
+            {
+                Ok(()) => report.loaded.push(Loaded {
+                    id: manifest.id.clone(),
+                    version: manifest.version.clone(),
+                    path: path.to_path_buf(),
+                }),
+                Err(error) => report.failed.push(Failure {
+                    id: Some(manifest.id.clone()),
+                    path: Some(path.to_path_buf()),
+                    error,
+                }),
+            }
+        }
+
+        let mut missing: Vec<&Id> = host
+            .extensions
+            .iter()
+            .filter(|(id, settings)| settings.enabled && !ids.contains(id))
+            .map(|(id, _)| id)
+            .collect();
+        missing.sort();
+
+        for id in missing {
+            report.failed.push(Failure {
+                id: Some(id.clone()),
+                path: None,
+                error: Error::ExtensionNotFound(id.clone()),
+            });
+        }
+
+        report
+    }
+
+    async fn load_entry(
+        &self,
+        id: &Id,
+        path: &Path,
+        manifest: Option<&crate::Manifest>,
+        settings: &ExtensionConfig,
+        trust: &Trust,
+        saved: Option<&Saved>,
+    ) -> Result<(), Error> {
+        let (mut config, sensitive) = settings.resolve()?;
+        let secrets = settings.resolve_secrets()?;
+        if let Some(saved) = saved {
+            config = saved.config(config);
+        }
+
+        let mut extension = match manifest {
+            Some(manifest) => crate::load_verified(manifest, config.to_string(), path.to_path_buf(), trust).await?,
+            None => {
+                trust.verify_unsigned(id)?;
+                crate::load(id.clone(), config.to_string(), path.to_path_buf()).await?
+            }
+        };
+        let saved_sensitive = saved.map(|saved| saved.sensitive.clone()).unwrap_or_default();
+        for field in sensitive.into_iter().chain(saved_sensitive) {
+            extension = extension.with_sensitive_field(field);
+        }
+        for (name, value) in secrets {
+            extension = extension.with_secret(name, value);
+        }
+        extension = extension.with_instances(settings.instances);
+        for (host, guest) in saved.map(|saved| saved.dirs.as_slice()).unwrap_or_default() {
+            extension = extension.with_dir(host, guest);
+        }
+
+        self.register(id.clone(), extension).await
+    }
+
+    /// What the registry runs now, to [`restore`](Self::restore) later
+    pub fn state(&self) -> State {
+        let extensions = self.shared.extensions.lock().unwrap();
+        let mut handles: Vec<&Handle> = extensions.values().collect();
+        handles.sort_by_key(|handle| handle.token);
+
+        State {
+            extensions: handles.into_iter().map(|handle| handle.saved.clone()).collect(),
+            ..State::default()
+        }
+    }
+
+    /// Write what the registry runs now to the state file at `path`
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.state().save(path)
+    }
+
+    /// Load and register the extensions in a saved `state`.
+    ///
+    /// Secrets and sensitive config fields are not saved, so `host` provides them as in
+    /// [`load_all`](Self::load_all); saved config fields take precedence over the host's. Extensions the host
+    /// config disables are skipped. Extensions whose component is gone, or whose manifest now has an incompatible
+    /// version or unmet [dependencies](crate::dependency), are reported as failed, and so are components `trust`
+    /// refuses.
+    pub async fn restore(&self, state: &State, host: &HostConfig, trust: &Trust) -> LoadReport {
+        let mut report = LoadReport::default();
+        let mut selected = Vec::new();
+
+        for saved in &state.extensions {
+            let settings = host.extension(&saved.id);
+            if !settings.enabled {
+                report.disabled.push(saved.id.clone());
+                continue;
+            }
+
+            match installed(saved).await {
+                Ok(manifest) => selected.push((saved, manifest, settings)),
+                Err(error) => report.failed.push(Failure {
+                    id: Some(saved.id.clone()),
+                    path: Some(saved.path.clone()),
+                    error,
+                }),
+            }
+        }
+
+        // Extensions without a manifest have no dependencies, so they can go first
+        let manifests: Vec<&crate::Manifest> = selected
+            .iter()
+            .filter_map(|(_, manifest, _)| manifest.as_ref())
+            .collect();
+        let (ordered, refused) = dependency::order(&manifests, &self.versions(None));
+        let ordered = selected
+            .iter()
+            .filter(|(_, manifest, _)| manifest.is_none())
+            .map(|(saved, _, _)| &saved.id)
+            .chain(ordered.iter().map(|manifest| &manifest.id));
+
+        for (id, error) in refused {
+            report.failed.push(Failure {
+                path: selected
+                    .iter()
+                    .find(|(saved, _, _)| saved.id == id)
+                    .map(|(saved, _, _)| saved.path.clone()),
+                id: Some(id),
+                error: error.into(),
+            });
+        }
+
+        for id in ordered {
+            let (saved, manifest, settings) = selected.iter().find(|(saved, _, _)| saved.id == *id).unwrap();
+
+            match self
+                .load_entry(id, &saved.path, manifest.as_ref(), settings, trust, Some(saved))
+                .await
+            {
+                Ok(()) => report.loaded.push(Loaded {
+                    id: id.clone(),
+                    version: manifest
+                        .as_ref()
+                        .map(|manifest| manifest.version.clone())
+                        .unwrap_or_default(),
+                    path: saved.path.clone(),
+                }),
+                Err(error) => report.failed.push(Failure {
+                    id: Some(id.clone()),
+                    path: Some(saved.path.clone()),
+                    error,
+                }),
+            }
         }
+
+        report
     }
 
-    /// Register an extension with the registry
-    pub async fn register(&mut self, id: Id, extension: Extension) -> Result<(), Error> {
-        if self.extensions.contains_key(&id) {
+    /// Register an extension with the registry.
+    ///
+    /// The extension is started in the background. Commands sent before it is connected are queued and delivered
+    /// once it is, and the extension is removed from the registry when it exits.
+    ///
+    /// An extension with a manifest is refused unless the host and the registered extensions meet its
+    /// [dependencies](crate::dependency).
+    pub async fn register(&self, id: Id, extension: Extension) -> Result<(), Error> {
+        if let Some(manifest) = extension.manifest() {
+            // A tenant's extensions depend on that tenant's instances of other extensions
+            dependency::check(manifest, &self.versions(tenant::of(&id)))?;
+        }
+
+        let (requests, requests_rx) = mpsc::unbounded();
+        let (controls, controls_rx) = mpsc::unbounded();
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+
+        let mut extensions = self.shared.extensions.lock().unwrap();
+        if extensions.contains_key(&id) {
             return Err(Error::RegistryAlreadyExists(format!(
                 "Extension {} already registered",
                 id
             )));
         }
+        // Counted under the lock, so that concurrent registrations cannot both take the last place
+        if let Some(name) = tenant::of(&id) {
+            let usage = self.shared.tenants.lock().unwrap().get(name).cloned();
+            if let Some(usage) = usage {
+                let registered = extensions
+                    .keys()
+                    .filter(|other| tenant::of(other) == Some(name))
+                    .count();
+                usage.check_extensions(name, registered)?;
+            }
+        }
+
+        let capabilities = extension.capabilities();
+        let subscriptions = extension.subscriptions();
+        let saved = Saved {
+            id: id.clone(),
+            ..extension.saved()
+        };
+        let extension = self.prepare(&id, extension);
+
+        // The task waits for this lock before it can remove itself, so it always finds its handle
+        let task = tokio::spawn(run(
+            id.clone(),
+            token,
+            extension,
+            requests_rx,
+            controls_rx,
+            self.shared.clone(),
+        ));
+        extensions.insert(
+            id,
+            Handle {
+                token,
+                requests,
+                controls,
+                capabilities,
+                subscriptions,
+                saved,
+                task,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Start an extension over from the same component.
+    ///
+    /// Commands it was processing are answered with an error; queued commands are delivered once it is ready again.
+    pub fn restart(&self, extension_id: &Id) -> Result<(), Error> {
+        self.shared.control(extension_id, Control::Restart)
+    }
+
+    /// Replace the component behind an extension, keeping its id and queued commands
+    pub fn reload(&self, extension_id: &Id, extension: Extension) -> Result<(), Error> {
+        let capabilities = extension.capabilities();
+        let subscriptions = extension.subscriptions();
+        let saved = Saved {
+            id: extension_id.clone(),
+            ..extension.saved()
+        };
+        let extension = self.prepare(extension_id, extension);
+        self.shared
+            .control(extension_id, Control::Reload(Box::new(extension)))?;
+        self.shared.purge_cache(extension_id);
+
+        if let Some(handle) = self.shared.extensions.lock().unwrap().get_mut(extension_id) {
+            handle.capabilities = capabilities;
+            handle.subscriptions = subscriptions;
+            handle.saved = saved;
+        }
+        Ok(())
+    }
+
+    /// Send a command to an extension and wait for its response
+    pub async fn call(&self, extension_id: &Id, command: Command) -> Result<Response, Error> {
+        self.shared
+            .dispatch(Call {
+                extension_id: extension_id.clone(),
+                command,
+                caller: None,
+                fresh: false,
+            })
+            .await
+    }
 
-        // Create the sipper
-        let sipper = extension.into_sipper();
+    /// Send a command to every extension the broadcast selects and collect their responses by extension id.
+    ///
+    /// Extensions are called concurrently. One that does not answer within the broadcast's timeout gets
+    /// [`Error::Timeout`], and errors from one extension do not affect the others.
+    pub async fn broadcast(&self, broadcast: Broadcast) -> BTreeMap<Id, Result<Response, Error>> {
+        let recipients: Vec<Id> = self
+            .shared
+            .extensions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, handle)| broadcast.selects(id, &handle.capabilities))
+            .map(|(id, _)| id.clone())
+            .collect();
 
-        // Forward events from this extension to the registry's event stream
-        let ext_id = id.clone();
-        let event_tx = self.event_tx.clone();
-        tokio::spawn(async move {
-            futures::pin_mut!(sipper);
-            while let Some(response) = sipper.next().await {
-                let _ = event_tx.unbounded_send((ext_id.clone(), response));
+        let calls = recipients.into_iter().map(|id| {
+            let command = broadcast.command.clone();
+            async move {
+                let result = tokio::time::timeout(broadcast.timeout, self.call(&id, command))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(Error::Timeout(format!(
+                            "Extension {} did not answer within {:?}",
+                            id, broadcast.timeout
+                        )))
+                    });
+                (id, result)
             }
-            sipper.await;
         });
 
-        Ok(())
+        futures::future::join_all(calls).await.into_iter().collect()
     }
 
-    /// Send a message to a specific extension
+    /// Publish an event from the host, returning how many subscribers it was delivered to.
+    ///
+    /// Extensions subscribed to the topic receive a [`Command::Event`] through the middleware; their answers to it are
+    /// discarded. Must be called within a Tokio runtime.
+    pub fn publish(&self, topic: impl Into<String>, payload: serde_json::Value) -> usize {
+        self.shared.publish(Message {
+            topic: topic.into(),
+            source: None,
+            payload,
+        })
+    }
+
+    /// Receive every event published on a topic matching `pattern`, by the host or any extension
+    pub fn subscribe(&self, pattern: impl Into<String>) -> mpsc::UnboundedReceiver<Message> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared.subscribers.lock().unwrap().push((pattern.into(), sender));
+        receiver
+    }
+
+    /// Send a message to a specific extension through the middleware. Its response is published on
+    /// [`events`](Self::events). Must be called within a Tokio runtime.
     pub fn send_message(&self, extension_id: &Id, message: Command) -> Result<(), Error> {
-        if let Some(sender) = self.extensions.get(extension_id) {
-            sender.unbounded_send(message).map_err(|e| Error::SendError(e))
-        } else {
-            Err(Error::RegistryNotFound(format!("Extension {} not found", extension_id)))
+        if !self.shared.extensions.lock().unwrap().contains_key(extension_id) {
+            return Err(Error::RegistryNotFound(format!("Extension {} not found", extension_id)));
         }
+
+        let call = Call {
+            extension_id: extension_id.clone(),
+            command: message,
+            caller: None,
+            fresh: false,
+        };
+        self.shared.post(call, Post::Event);
+        Ok(())
+    }
+
+    /// Execute a tool by its qualified id, e.g. `polygon.call_endpoint`, on whichever extension provides it
+    pub async fn execute(&self, tool_id: &str, params: serde_json::Value) -> Result<Response, Error> {
+        self.shared.execute_tool(tool_id, params, false).await
+    }
+
+    /// Execute a tool like [`execute`](Self::execute), but bypass the [tool cache](Self::with_tool_cache)
+    pub async fn execute_fresh(&self, tool_id: &str, params: serde_json::Value) -> Result<Response, Error> {
+        self.shared.execute_tool(tool_id, params, true).await
+    }
+
+    /// Execute every call of `batch`, running up to its concurrency limit at once, and return the results in the
+    /// order of the calls.
+    ///
+    /// Calls to different extensions run in parallel. Calls to the same extension run on as many of its
+    /// [instances](Extension::with_instances) as are idle, and queue up for the next free one. Each call goes
+    /// through middleware and the tool cache like [`execute`](Self::execute), and fails on its own.
+    pub async fn execute_batch(&self, batch: Batch) -> Vec<Result<Response, Error>> {
+        batch::run(
+            batch,
+            |call| async move { self.execute(&call.tool_id, call.params).await },
+        )
+        .await
+    }
+
+    /// Run the steps of `pipeline` in order, starting from `input`, and report what each one did.
+    ///
+    /// Tools are executed like [`execute`](Self::execute) does, through middleware and the tool cache.
+    pub async fn run_pipeline(&self, pipeline: &Pipeline, input: serde_json::Value) -> Trace {
+        pipeline::run(pipeline, input, |tool, params| async move {
+            self.execute(&tool, params).await
+        })
+        .await
+    }
+
+    /// Execute a tool repeatedly, as `schedule` says. Must be called within a Tokio runtime.
+    ///
+    /// Each result goes to the schedule's callback if it has one, and is otherwise published on
+    /// [`events`](Self::events) as coming from the extension that provides the tool, with failures as
+    /// [`Response::Error`]. The tool does not have to exist yet; runs fail until it does.
+    pub fn schedule(&self, schedule: Schedule) -> ScheduleId {
+        let id = self.next_schedule.fetch_add(1, Ordering::Relaxed);
+        let shared = Arc::downgrade(&self.shared);
+        let (tool, params, callback) = (
+            schedule.tool.clone(),
+            schedule.params.clone(),
+            schedule.callback.clone(),
+        );
+
+        let fire = move || {
+            let (shared, tool, params, callback) = (shared.clone(), tool.clone(), params.clone(), callback.clone());
+            async move {
+                let Some(shared) = shared.upgrade() else {
+                    return false;
+                };
+                let result = shared.execute_tool(&tool, params, false).await;
+
+                match callback {
+                    Some(callback) => callback(id, result),
+                    None => {
+                        let extension_id = shared.resolve(&tool).map_or(tool, |(extension_id, _)| extension_id);
+                        let response = result.unwrap_or_else(|e| Response::Error(e.to_string()));
+                        let _ = shared.event_tx.unbounded_send((extension_id, response));
+                    }
+                }
+                true
+            }
+        };
+
+        self.schedules
+            .lock()
+            .unwrap()
+            .insert(id, schedule::spawn(schedule, fire));
+        id
+    }
+
+    /// Stop running a schedule until it is [resumed](Self::resume_schedule)
+    pub fn pause_schedule(&self, id: ScheduleId) -> Result<(), Error> {
+        self.with_schedule(id, |control| control.pause(true))
+    }
+
+    /// Run a paused schedule again. Runs it missed meanwhile are handled by its [`Missed`](schedule::Missed) policy.
+    pub fn resume_schedule(&self, id: ScheduleId) -> Result<(), Error> {
+        self.with_schedule(id, |control| control.pause(false))
+    }
+
+    /// Stop and remove a schedule. A run in progress is abandoned.
+    pub fn cancel_schedule(&self, id: ScheduleId) -> Result<(), Error> {
+        self.schedules
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(drop)
+            .ok_or_else(|| Error::RegistryNotFound(format!("Schedule {} not found", id)))
+    }
+
+    /// Where every schedule stands, by id
+    pub fn schedules(&self) -> BTreeMap<ScheduleId, schedule::Status> {
+        self.schedules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, control)| (*id, control.status()))
+            .collect()
+    }
+
+    fn with_schedule(&self, id: ScheduleId, f: impl FnOnce(&schedule::Control)) -> Result<(), Error> {
+        let schedules = self.schedules.lock().unwrap();
+        let control = schedules
+            .get(&id)
+            .ok_or_else(|| Error::RegistryNotFound(format!("Schedule {} not found", id)))?;
+        f(control);
+        Ok(())
     }
 
-    /// Get a stream of all events from all extensions
+    /// Every tool provided by a registered extension, with qualified ids, sorted by id
+    pub fn tools(&self) -> Vec<ToolInfo> {
+        let mut tools: Vec<ToolInfo> = self
+            .shared
+            .tools
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(extension_id, tools)| {
+                tools.iter().map(move |tool| ToolInfo {
+                    id: qualify(extension_id, &tool.id),
+                    ..tool.clone()
+                })
+            })
+            .collect();
+
+        tools.sort_by(|a, b| a.id.cmp(&b.id));
+        tools
+    }
+
+    /// Get a stream of all events from all extensions.
+    ///
+    /// These are responses to [`send_message`](Self::send_message) and anything else an extension sends unasked.
+    /// Changes in the state of extensions are published on [`lifecycle`](Self::lifecycle) instead.
     pub fn events(&mut self) -> impl Stream<Item = (Id, Response)> + '_ {
         &mut self.event_rx
     }
 
-    /// Unregister an extension
-    pub fn unregister(&mut self, extension_id: &Id) -> Result<(), Error> {
-        self.extensions
-            .remove(extension_id)
-            .map(|_| ())
-            .ok_or_else(|| Error::RegistryNotFound(format!("Extension {} not found", extension_id)))
+    /// Get a stream of what happens to every registered extension, from loading to being unregistered
+    pub fn lifecycle(&mut self) -> impl Stream<Item = (Id, Lifecycle)> + '_ {
+        &mut self.lifecycle_rx
+    }
+
+    /// Unregister an extension, waiting until it has stopped.
+    ///
+    /// The extension is stopped even if it is in the middle of a command, and its store and instance are dropped.
+    /// Callers still waiting on it get [`Error::ExtensionUnregistered`].
+    pub async fn unregister(&self, extension_id: &Id) -> Result<(), Error> {
+        let removed = self.shared.extensions.lock().unwrap().remove(extension_id);
+        self.shared.tools.lock().unwrap().remove(extension_id);
+        self.shared.health.lock().unwrap().remove(extension_id);
+
+        let Handle { task, .. } =
+            removed.ok_or_else(|| Error::RegistryNotFound(format!("Extension {} not found", extension_id)))?;
+        self.shared.purge_cache(extension_id);
+
+        // Dropping the handle's senders tells the task to stop
+        task.await
+            .map_err(|e| Error::ExtensionExited(format!("{}: {}", extension_id, e)))
+    }
+
+    /// The manifest version of every registered extension that has one.
+    ///
+    /// Only extensions of `tenant` are included, by their id within it, or only those of no tenant for `None`.
+    fn versions(&self, tenant: Option<&str>) -> HashMap<Id, String> {
+        self.shared
+            .extensions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| tenant::of(id) == tenant)
+            .filter_map(|(id, handle)| {
+                let id = match tenant {
+                    Some(tenant) => id.strip_prefix(tenant)?.strip_prefix(tenant::SEPARATOR)?,
+                    None => id,
+                };
+                Some((id.to_string(), handle.saved.version.clone()?))
+            })
+            .collect()
+    }
+
+    /// Connect an extension to be run as `id` to the registry
+    fn prepare(&self, id: &Id, extension: Extension) -> Extension {
+        let extension = extension
+            .with_peers(self.peers(id))
+            .with_metrics(self.shared.recorder(id));
+
+        if self.fuel {
+            extension.with_fuel_metering()
+        } else {
+            extension
+        }
+    }
+
+    /// What the extension registered as `id` may call and publish to
+    fn peers(&self, id: &Id) -> Peers {
+        Peers {
+            shared: Arc::downgrade(&self.shared),
+            timeout: self.call_timeout,
+            tenant: tenant::of(id).map(str::to_string),
+        }
+    }
+
+    /// The extensions of `tenant`, which are isolated from those of other tenants.
+    ///
+    /// Tenants need no setup: any name without a [`SEPARATOR`](tenant::SEPARATOR) is a tenant as soon as it
+    /// registers an extension.
+    pub fn tenant(&self, name: impl Into<String>) -> Tenant<'_> {
+        let name = name.into();
+        let usage = self
+            .shared
+            .tenants
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .clone();
+        Tenant::new(self, name, usage)
+    }
+
+    /// Names of the tenants with registered extensions
+    pub fn tenants(&self) -> Vec<String> {
+        let mut tenants: Vec<String> = self
+            .list_extensions()
+            .iter()
+            .filter_map(|id| tenant::of(id).map(str::to_string))
+            .collect();
+        tenants.sort();
+        tenants.dedup();
+        tenants
+    }
+
+    pub(crate) fn forget_tenant(&self, name: &str) {
+        self.shared.tenants.lock().unwrap().remove(name);
     }
 
     /// Get list of registered extension IDs
     pub fn list_extensions(&self) -> Vec<Id> {
-        self.extensions.keys().cloned().collect()
+        self.shared.extensions.lock().unwrap().keys().cloned().collect()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    #[tokio::test]
-    async fn test_registry_basic() {
-        let registry = Registry::new();
+impl Drop for Registry {
+    /// Stop every extension. Their tasks share state with the registry, so they would otherwise outlive it.
+    fn drop(&mut self) {
+        self.shared.extensions.lock().unwrap().clear();
+    }
+}
 
-        // Would add actual tests here with mock extensions
-        assert_eq!(registry.list_extensions().len(), 0);
+/// Lets an extension execute the tools of the other extensions in its registry
+#[derive(Clone)]
+pub(crate) struct Peers {
+    shared: Weak<Shared>,
+    timeout: Duration,
+    /// The tenant of the extension, whose extensions are the only ones it sees
+    tenant: Option<String>,
+}
+
+impl Peers {
+    /// Execute `tool_id` on `target` on behalf of `caller` and return the JSON result.
+    ///
+    /// A call that leads back to an extension already waiting on the chain is only answered if that extension has an
+    /// instance to spare, and would otherwise never be. Such calls are refused instead.
+    pub(crate) async fn execute(
+        &self,
+        caller: &Id,
+        target: &Id,
+        tool_id: &str,
+        params: serde_json::Value,
+    ) -> Result<String, String> {
+        let shared = self.shared.upgrade().ok_or("The registry is gone")?;
+        let (caller, target) = (&self.scope(caller), &self.scope(target));
+        let _waiting = Waiting::start(&shared, caller, target)?;
+
+        let call = Call {
+            extension_id: target.clone(),
+            command: Command::ExecuteTool {
+                tool_id: tool_id.to_string(),
+                params,
+            },
+            caller: Some(caller.clone()),
+            fresh: false,
+        };
+        let response = tokio::time::timeout(self.timeout, shared.dispatch(call))
+            .await
+            .map_err(|_| format!("{}.{} did not answer within {:?}", target, tool_id, self.timeout))?
+            .map_err(|e| e.to_string())?;
+
+        match response {
+            Response::ToolResult { result, .. } => Ok(result.to_string()),
+            Response::Error(error) => Err(error),
+            response => Err(format!("Unexpected response from {}: {:?}", target, response)),
+        }
+    }
+}
+
+impl Peers {
+    /// Publish an event on behalf of an extension
+    pub(crate) fn publish(&self, mut message: Message) {
+        message.source = message.source.map(|source| self.scope(&source));
+        if let Some(shared) = self.shared.upgrade() {
+            shared.publish(message);
+        }
+    }
+
+    /// The registry id of the extension `id` as the extension sees it, within its tenant
+    fn scope(&self, id: &Id) -> Id {
+        match &self.tenant {
+            Some(tenant) => tenant::scoped(tenant, id),
+            None => id.clone(),
+        }
+    }
+}
+
+/// An extension waiting on another in a tool call, for as long as this lives
+struct Waiting<'a> {
+    shared: &'a Shared,
+    caller: Id,
+    target: Id,
+}
+
+impl<'a> Waiting<'a> {
+    fn start(shared: &'a Shared, caller: &Id, target: &Id) -> Result<Self, String> {
+        let mut waiting = shared.waiting.lock().unwrap();
+
+        // Follow every call in progress from the target, looking for one back to the caller
+        let mut chains = vec![vec![caller, target]];
+        let mut seen = HashSet::new();
+        while let Some(chain) = chains.pop() {
+            let id = chain[chain.len() - 1];
+            if id == caller {
+                let chain: Vec<&str> = chain.iter().map(|id| id.as_str()).collect();
+                return Err(format!("Calling {} would deadlock: {}", target, chain.join(" -> ")));
+            }
+            if !seen.insert(id) {
+                continue;
+            }
+            for next in waiting.get(id).into_iter().flat_map(|targets| targets.keys()) {
+                let mut chain = chain.clone();
+                chain.push(next);
+                chains.push(chain);
+            }
+        }
+
+        *waiting
+            .entry(caller.clone())
+            .or_default()
+            .entry(target.clone())
+            .or_default() += 1;
+        Ok(Self {
+            shared,
+            caller: caller.clone(),
+            target: target.clone(),
+        })
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.shared.waiting.lock().unwrap();
+        let Some(targets) = waiting.get_mut(&self.caller) else {
+            return;
+        };
+        if let Some(calls) = targets.get_mut(&self.target) {
+            *calls -= 1;
+            if *calls == 0 {
+                targets.remove(&self.target);
+            }
+        }
+        if targets.is_empty() {
+            waiting.remove(&self.caller);
+        }
+    }
+}
+
+impl Shared {
+    /// Execute a tool by its qualified id, naming it the same way in the result
+    async fn execute_tool(&self, tool_id: &str, params: serde_json::Value, fresh: bool) -> Result<Response, Error> {
+        let (extension_id, local_id) = self
+            .resolve(tool_id)
+            .ok_or_else(|| Error::RegistryNotFound(format!("Tool {} not found", tool_id)))?;
+
+        let call = Call {
+            extension_id,
+            command: Command::ExecuteTool {
+                tool_id: local_id,
+                params,
+            },
+            caller: None,
+            fresh,
+        };
+
+        Ok(match self.dispatch(call).await? {
+            Response::ToolResult { result, .. } => Response::ToolResult {
+                tool_id: tool_id.to_string(),
+                result,
+            },
+            response => response,
+        })
+    }
+
+    /// The extension that provides a qualified tool id, and the tool's id within that extension
+    fn resolve(&self, tool_id: &str) -> Option<(Id, String)> {
+        let tools = self.tools.lock().unwrap();
+
+        tools.iter().find_map(|(extension_id, tools)| {
+            let local_id = tool_id
+                .strip_prefix(extension_id.as_str())?
+                .strip_prefix(NAMESPACE_SEPARATOR)?;
+
+            tools
+                .iter()
+                .any(|tool| tool.id == local_id)
+                .then(|| (extension_id.clone(), local_id.to_string()))
+        })
+    }
+
+    /// Where the metrics of the extension `id` are recorded
+    fn recorder(&self, id: &Id) -> Recorder {
+        self.metrics.lock().unwrap().entry(id.clone()).or_default().clone()
+    }
+
+    /// Deliver `message` through the middleware to every subscriber but its publisher, returning how many it reached.
+    ///
+    /// Messages from an extension of a tenant only reach that tenant's extensions.
+    fn publish(self: &Arc<Self>, message: Message) -> usize {
+        let tenant = message.source.as_deref().map(tenant::of);
+
+        let recipients: Vec<Id> = self
+            .extensions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, handle)| {
+                let subscribed = handle
+                    .subscriptions
+                    .iter()
+                    .any(|pattern| bus::matches(pattern, &message.topic));
+                let visible = tenant.is_none_or(|tenant| tenant == tenant::of(id));
+                subscribed && visible && message.source.as_ref() != Some(*id) && !handle.requests.is_closed()
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut delivered = recipients.len();
+        for id in recipients {
+            let call = Call {
+                extension_id: id,
+                command: Command::Event {
+                    topic: message.topic.clone(),
+                    source: message.source.clone(),
+                    payload: message.payload.clone(),
+                },
+                caller: message.source.clone(),
+                fresh: false,
+            };
+            self.post(call, Post::Discard);
+        }
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, sender)| !sender.is_closed());
+        for (pattern, sender) in subscribers.iter() {
+            if bus::matches(pattern, &message.topic) && sender.unbounded_send(message.clone()).is_ok() {
+                delivered += 1;
+            }
+        }
+
+        delivered
+    }
+
+    /// Dispatch a command without waiting for its reply, which goes to `post`.
+    ///
+    /// The dispatch runs right away until it first has to wait, so a command that middleware passes straight on is
+    /// queued for the extension before this returns, in the order commands are posted. The rest runs in a task. A
+    /// wakeup for that first run is forwarded to the task, so middleware that only set it up then is still woken.
+    fn post(self: &Arc<Self>, call: Call, post: Post) {
+        let shared = self.clone();
+        let mut dispatch = Box::pin(async move {
+            let extension_id = call.extension_id.clone();
+            let response = shared
+                .dispatch(call)
+                .await
+                .unwrap_or_else(|e| Response::Error(e.to_string()));
+
+            if let Post::Event = post {
+                let _ = shared.event_tx.unbounded_send((extension_id, response));
+            }
+        });
+
+        let forward = Arc::new(Forward::default());
+        let waker = std::task::Waker::from(forward.clone());
+        if dispatch
+            .as_mut()
+            .poll(&mut std::task::Context::from_waker(&waker))
+            .is_pending()
+        {
+            tokio::spawn(std::future::poll_fn(move |context| {
+                forward.0.register(context.waker());
+                dispatch.as_mut().poll(context)
+            }));
+        }
+    }
+
+    /// Send a command through the middleware to the extension, and its reply back through the middleware
+    async fn dispatch(&self, call: Call) -> Result<Response, Error> {
+        let _counted = self.count_call(&call.extension_id)?;
+        let middleware = self.middleware.lock().unwrap().clone();
+        let send = |call: Call| -> Reply<'_> { Box::pin(self.send(call)) };
+        Next::new(&middleware, &send).run(call).await
+    }
+
+    /// Count a call to `extension_id` against the quota of its tenant, if it belongs to one, until the result is
+    /// dropped
+    fn count_call(&self, extension_id: &str) -> Result<Option<tenant::Call>, Error> {
+        let Some(name) = tenant::of(extension_id) else {
+            return Ok(None);
+        };
+        let usage = self.tenants.lock().unwrap().get(name).cloned();
+        usage.map(|usage| usage.start_call(name)).transpose()
+    }
+
+    /// Answer a call from the tool cache if it can be, or else send it and store the result if it may be cached
+    async fn send(&self, call: Call) -> Result<Response, Error> {
+        let Some((cache, key, ttl)) = self.cacheable(&call) else {
+            return self.deliver(call).await;
+        };
+
+        if !call.fresh
+            && let Some(result) = cache.get(&call.extension_id, &key).await
+            && let Command::ExecuteTool { tool_id, .. } = call.command
+        {
+            return Ok(Response::ToolResult { tool_id, result });
+        }
+
+        let extension_id = call.extension_id.clone();
+        let response = self.deliver(call).await?;
+        if let Response::ToolResult { result, .. } = &response {
+            cache.put(&extension_id, key, result.clone(), ttl).await;
+        }
+        Ok(response)
+    }
+
+    /// The cache, key and TTL for a call to a cacheable tool
+    fn cacheable(&self, call: &Call) -> Option<(Cache, String, Duration)> {
+        let Command::ExecuteTool { tool_id, params } = &call.command else {
+            return None;
+        };
+        let cache = self.cache.lock().unwrap().clone()?;
+
+        let declared = self
+            .tools
+            .lock()
+            .unwrap()
+            .get(&call.extension_id)?
+            .iter()
+            .find(|tool| tool.id == *tool_id && tool.cacheable)?
+            .cache_ttl;
+        let ttl = declared
+            .map(Duration::from_secs)
+            .or(cache.ttl)
+            .filter(|ttl| !ttl.is_zero())?;
+
+        let version = self
+            .extensions
+            .lock()
+            .unwrap()
+            .get(&call.extension_id)
+            .and_then(|handle| handle.saved.version.clone())
+            .unwrap_or_default();
+        let key = Cache::key(&call.extension_id, &version, tool_id, params);
+        Some((cache, key, ttl))
+    }
+
+    /// Drop the cached tool results of an extension whose component is replaced or gone
+    fn purge_cache(&self, id: &Id) {
+        let cache = self.cache.lock().unwrap().clone();
+        if let Some(cache) = cache
+            && let Err(e) = cache.purge(id)
+        {
+            eprintln!("Failed to purge cached results of {}: {}", id, e);
+        }
+    }
+
+    /// Send a command to the extension and wait for its reply
+    async fn deliver(&self, call: Call) -> Result<Response, Error> {
+        let (reply, response) = oneshot::channel();
+        self.request(
+            &call.extension_id,
+            Request {
+                command: call.command,
+                reply: Pending::Reply(reply),
+            },
+        )?;
+
+        response
+            .await
+            .map_err(|_| Error::ExtensionExited(call.extension_id.clone()))?
+    }
+
+    /// Queue a request for the extension
+    fn request(&self, extension_id: &Id, request: Request) -> Result<(), Error> {
+        let extensions = self.extensions.lock().unwrap();
+        let handle = extensions
+            .get(extension_id)
+            .ok_or_else(|| Error::RegistryNotFound(format!("Extension {} not found", extension_id)))?;
+
+        handle
+            .requests
+            .unbounded_send(request)
+            .map_err(|_| Error::ExtensionExited(extension_id.clone()))
+    }
+
+    /// Send a control message to the task driving `id`
+    fn control(&self, id: &Id, control: Control) -> Result<(), Error> {
+        let extensions = self.extensions.lock().unwrap();
+        let handle = extensions
+            .get(id)
+            .ok_or_else(|| Error::RegistryNotFound(format!("Extension {} not found", id)))?;
+
+        handle
+            .controls
+            .unbounded_send(control)
+            .map_err(|_| Error::ExtensionExited(id.clone()))
+    }
+
+    /// Whether `token` is still the registration of `id`
+    fn is_current(&self, id: &Id, token: u64) -> bool {
+        self.extensions
+            .lock()
+            .unwrap()
+            .get(id)
+            .is_some_and(|handle| handle.token == token)
+    }
+}
+
+fn qualify(extension_id: &str, tool_id: &str) -> String {
+    format!("{extension_id}{NAMESPACE_SEPARATOR}{tool_id}")
+}
+
+/// Drive an extension, delivering requests to it and routing its responses.
+///
+/// An extension answers each command with exactly one response, in order, so responses are matched to requests
+/// first-in first-out. Once connected, the extension is asked for its tools to fill the catalog, and every tool list
+/// it returns later keeps the catalog current.
+///
+/// Metadata, connection and startup failures are not responses to commands; they are published as [`Lifecycle`]
+/// events.
+async fn run(
+    id: Id,
+    token: u64,
+    mut extension: Extension,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+    shared: Arc<Shared>,
+) {
+    let lifecycle = |event: Lifecycle| {
+        let _ = shared.lifecycle_tx.unbounded_send((id.clone(), event));
+    };
+
+    lifecycle(Lifecycle::Loading);
+    let mut sipper = Box::pin(extension.clone().into_sipper());
+    let mut connection: Option<mpsc::UnboundedSender<Command>> = None;
+    let mut metadata: Option<Metadata> = None;
+    let mut pending: VecDeque<Pending> = VecDeque::new();
+    // The last error the extension sent, which explains why it stopped if it does
+    let mut last_error: Option<String> = None;
+
+    let exit = loop {
+        // Controls come first, so commands sent after a restart reach the new instance
+        tokio::select! {
+            biased;
+
+            control = controls.next() => {
+                let event = match control {
+                    Some(Control::Restart) => {
+                        shared.recorder(&id).restart();
+                        Lifecycle::Restarted
+                    }
+                    Some(Control::Reload(new)) => {
+                        extension = *new;
+                        Lifecycle::Reloaded
+                    }
+                    None => break Lifecycle::Unregistered,
+                };
+
+                // Dropping the old sipper drops its store, which stops the old instance
+                sipper = Box::pin(extension.clone().into_sipper());
+                shared.health.lock().unwrap().remove(&id);
+                connection = None;
+                metadata = None;
+                last_error = None;
+                let restarted = Error::ExtensionExited(format!("{} (restarted)", id));
+                answer(std::mem::take(&mut pending), &restarted);
+
+                lifecycle(event);
+                lifecycle(Lifecycle::Loading);
+            }
+            response = sipper.next() => {
+                if response.is_some() && let Some(status) = shared.health.lock().unwrap().get_mut(&id) {
+                    status.last_seen = SystemTime::now();
+                }
+
+                match response {
+                    Some(Response::Metadata { id: extension_id, name, version, description }) => {
+                        metadata = Some(Metadata { id: extension_id, name, version, description });
+                    }
+                    Some(Response::Connected(sender)) => {
+                        if sender.unbounded_send(Command::ListTools).is_ok() {
+                            pending.push_back(Pending::Catalog);
+                        }
+                        connection = Some(sender);
+                        if shared.is_current(&id, token) {
+                            shared.health.lock().unwrap().insert(id.clone(), Status::new());
+                        }
+                        if let Some(metadata) = metadata.clone() {
+                            lifecycle(Lifecycle::Ready(metadata));
+                        }
+                    }
+                    Some(Response::Error(error)) if connection.is_none() => {
+                        last_error = Some(error);
+                    }
+                    Some(response) => {
+                        last_error = match &response {
+                            Response::Error(error) => Some(error.clone()),
+                            _ => None,
+                        };
+
+                        if let Response::ToolList(tools) = &response
+                            && shared.is_current(&id, token)
+                        {
+                            shared.tools.lock().unwrap().insert(id.clone(), tools.clone());
+                        }
+
+                        match pending.pop_front() {
+                            Some(Pending::Reply(reply) | Pending::Probe(reply)) => {
+                                let _ = reply.send(Ok(response));
+                            }
+                            Some(Pending::Catalog) => {
+                                // Extensions that do not list tools simply have none
+                            }
+                            None => {
+                                let _ = shared.event_tx.unbounded_send((id.clone(), response));
+                            }
+                        }
+                    }
+                    None => {
+                        let reason = last_error.take().unwrap_or_else(|| "stopped".to_string());
+                        break Lifecycle::Crashed(Error::ExtensionExited(format!("{}: {}", id, reason)));
+                    }
+                }
+            }
+            request = requests.next(), if connection.is_some() => {
+                let (Some(Request { command, reply }), Some(sender)) = (request, &connection) else {
+                    break Lifecycle::Unregistered;
+                };
+                match sender.unbounded_send(command) {
+                    Ok(()) => pending.push_back(reply),
+                    Err(_) => answer([reply], &Error::ExtensionExited(id.clone())),
+                }
+            }
+        }
+
+        if let Some(status) = shared.health.lock().unwrap().get_mut(&id) {
+            status.in_flight = pending.iter().filter(|p| !matches!(p, Pending::Probe(_))).count();
+        }
+    };
+
+    // Free the store and instance before anyone is told the extension is gone
+    drop(sipper);
+
+    let error = match &exit {
+        Lifecycle::Unregistered => Error::ExtensionUnregistered(id.clone()),
+        _ => Error::ExtensionExited(id.clone()),
+    };
+    answer(pending, &error);
+
+    // Commands that never reached the extension
+    requests.close();
+    while let Ok(Request { reply, .. }) = requests.try_recv() {
+        answer([reply], &error);
+    }
+
+    {
+        let mut extensions = shared.extensions.lock().unwrap();
+        if extensions.get(&id).is_some_and(|handle| handle.token == token) {
+            extensions.remove(&id);
+        }
+        if !extensions.contains_key(&id) {
+            shared.tools.lock().unwrap().remove(&id);
+            shared.health.lock().unwrap().remove(&id);
+        }
+    }
+
+    lifecycle(exit);
+}
+
+/// The manifest installed next to a saved extension's component, if it was saved with one, once it is checked to
+/// still be the same extension in a compatible version
+async fn installed(saved: &Saved) -> Result<Option<crate::Manifest>, Error> {
+    if !saved.path.exists() {
+        return Err(Error::ExtensionNotFound(saved.path.display().to_string()));
+    }
+    let Some(version) = &saved.version else {
+        return Ok(None);
+    };
+
+    let manifest_path = saved.path.with_file_name("manifest.toml");
+    let manifest = extension::parse_manifest(&manifest_path).await?;
+    if manifest.id != saved.id {
+        return Err(Error::StateError(format!(
+            "{} now holds extension {} instead of {}",
+            saved.path.display(),
+            manifest.id,
+            saved.id
+        )));
+    }
+
+    // A version with the same major, or minor before 1.0, is a drop-in replacement
+    let compatible = semver::VersionReq::parse(&format!("^{}", version))
+        .ok()
+        .zip(semver::Version::parse(&manifest.version).ok())
+        .is_some_and(|(requirement, installed)| requirement.matches(&installed));
+    if !compatible {
+        return Err(Error::StateError(format!(
+            "{} was saved at version {}, which is not compatible with the installed {}",
+            saved.id, version, manifest.version
+        )));
+    }
+
+    Ok(Some(manifest))
+}
+
+/// Probe the extensions of a registry every `check.interval`, until the registry is dropped
+async fn monitor(shared: Weak<Shared>, check: HealthCheck) {
+    let mut ticks = tokio::time::interval(check.interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+
+        let ids: Vec<Id> = shared.health.lock().unwrap().keys().cloned().collect();
+        futures::future::join_all(ids.into_iter().map(|id| probe(&shared, id, &check))).await;
+    }
+}
+
+/// Wakes the task a posted dispatch moved to, once it has one, see [`Shared::post`]
+#[derive(Default)]
+struct Forward(AtomicWaker);
+
+impl std::task::Wake for Forward {
+    fn wake(self: Arc<Self>) {
+        self.0.wake();
+    }
+}
+
+/// Ask an extension for its view and record how it answered
+async fn probe(shared: &Shared, id: Id, check: &HealthCheck) {
+    let (reply, response) = oneshot::channel();
+    let sent = shared.extensions.lock().unwrap().get(&id).is_some_and(|handle| {
+        handle
+            .requests
+            .unbounded_send(Request {
+                command: Command::View,
+                reply: Pending::Probe(reply),
+            })
+            .is_ok()
+    });
+    if !sent {
+        return;
+    }
+
+    let start = Instant::now();
+    let answered = matches!(tokio::time::timeout(check.timeout, response).await, Ok(Ok(Ok(_))));
+
+    let unresponsive = {
+        let mut health = shared.health.lock().unwrap();
+        let Some(status) = health.get_mut(&id) else {
+            return;
+        };
+        status.probed(answered.then(|| start.elapsed()), check);
+        status.health == Health::Unresponsive
+    };
+
+    if unresponsive && check.restart {
+        eprintln!("Extension {} is unresponsive, restarting", id);
+        let _ = shared.control(&id, Control::Restart);
+    }
+}
+
+/// Answer every caller still waiting on an extension that will not reply
+fn answer(pending: impl IntoIterator<Item = Pending>, error: &Error) {
+    for pending in pending {
+        if let Pending::Reply(reply) | Pending::Probe(reply) = pending {
+            let _ = reply.send(Err(error.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SignatureError;
+    use crate::signature::{Policy, Signature};
+
+    async fn kv() -> Extension {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/marketplace/build/emporium_kv/emporium_kv.wasm"
+        );
+        crate::load("kv".to_string(), String::new(), path.into()).await.unwrap()
+    }
+
+    /// Load a test extension written in the component text format, `tests/fixtures/{name}.wat`
+    async fn fixture(name: &str) -> Extension {
+        static BUILT: AtomicU64 = AtomicU64::new(0);
+        let source = format!("{}/tests/fixtures/{}.wat", env!("CARGO_MANIFEST_DIR"), name);
+        // A file per load, since tests run concurrently
+        let path = std::env::temp_dir().join(format!(
+            "emporium-{}-{}-{}.wasm",
+            name,
+            std::process::id(),
+            BUILT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, wat::parse_file(source).unwrap()).unwrap();
+        let extension = crate::load(name.to_string(), "{}".to_string(), path.clone())
+            .await
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        extension
+    }
+
+    #[tokio::test]
+    async fn test_registry_basic() {
+        let registry = Registry::new();
+
+        assert_eq!(registry.list_extensions().len(), 0);
+        assert!(matches!(
+            registry.call(&"kv".to_string(), Command::ListTools).await,
+            Err(Error::RegistryNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_call_routes_to_extension() {
+        let mut registry = Registry::new();
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        assert_eq!(registry.list_extensions(), vec![id.clone()]);
+        assert!(matches!(
+            registry.register(id.clone(), kv().await).await,
+            Err(Error::RegistryAlreadyExists(_))
+        ));
+
+        // The KV guest only speaks its own protocol, so every command comes back as an error from the guest
+        let response = registry.call(&id, Command::ListTools).await.unwrap();
+        assert!(matches!(response, Response::Error(ref e) if e.contains("Invalid message")));
+
+        registry.send_message(&id, Command::Custom("ping".to_string())).unwrap();
+        let response = registry.call(&id, Command::ListTools).await.unwrap();
+        assert!(matches!(response, Response::Error(_)));
+
+        // Only the response to the fire-and-forget message
+        let (_, event) = registry.events().next().await.unwrap();
+        assert!(matches!(event, Response::Error(_)));
+
+        registry.unregister(&id).await.unwrap();
+        assert!(registry.list_extensions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let mut registry = Registry::new();
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        // Queued until the extension is ready
+        registry.call(&id, Command::ListTools).await.unwrap();
+        registry.restart(&id).unwrap();
+        registry.call(&id, Command::ListTools).await.unwrap();
+        registry.reload(&id, kv().await).unwrap();
+        registry.call(&id, Command::ListTools).await.unwrap();
+        registry.unregister(&id).await.unwrap();
+
+        let events: Vec<Lifecycle> = registry.lifecycle().take(9).map(|(_, event)| event).collect().await;
+        assert!(matches!(events[0], Lifecycle::Loading));
+        assert!(matches!(&events[1], Lifecycle::Ready(metadata) if metadata.id == "kv"));
+        assert!(matches!(events[2], Lifecycle::Restarted));
+        assert!(matches!(events[3], Lifecycle::Loading));
+        assert!(matches!(events[4], Lifecycle::Ready(_)));
+        assert!(matches!(events[5], Lifecycle::Reloaded));
+        assert!(matches!(events[6], Lifecycle::Loading));
+        assert!(matches!(events[7], Lifecycle::Ready(_)));
+        assert!(events[8].is_final());
+
+        // A component that cannot start crashes instead of becoming ready
+        let path = std::env::temp_dir().join(format!("emporium-broken-{}.wasm", std::process::id()));
+        std::fs::write(&path, b"not a component").unwrap();
+        let broken = crate::load(id.clone(), String::new(), path.clone()).await.unwrap();
+        registry.register(id.clone(), broken).await.unwrap();
+
+        let events: Vec<Lifecycle> = registry.lifecycle().take(2).map(|(_, event)| event).collect().await;
+        assert!(matches!(events[0], Lifecycle::Loading));
+        assert!(matches!(&events[1], Lifecycle::Crashed(Error::ExtensionExited(e)) if e.starts_with("kv: ")));
+        assert!(registry.list_extensions().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unregister_stops_extension() {
+        let registry = Registry::new();
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        // Queued while the extension is still loading, then canceled
+        let (response, unregistered) = tokio::join!(registry.call(&id, Command::ListTools), registry.unregister(&id));
+        unregistered.unwrap();
+        assert!(matches!(response, Err(Error::ExtensionUnregistered(_))));
+
+        // The task has let go of everything it shared with the registry
+        assert_eq!(Arc::strong_count(&registry.shared), 1);
+        assert!(matches!(
+            registry.unregister(&id).await,
+            Err(Error::RegistryNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unregister_stops_busy_extension() {
+        let registry = Registry::new();
+        let id = "spin".to_string();
+        registry
+            .register(id.clone(), fixture("spin").await.with_instances(2))
+            .await
+            .unwrap();
+
+        // Both instances loop without calling the host, yet the single test thread still gets to run the timer
+        let command = Command::ExecuteTool {
+            tool_id: "spin".to_string(),
+            params: serde_json::json!({}),
+        };
+        tokio::select! {
+            _ = futures::future::join(registry.call(&id, command.clone()), registry.call(&id, command)) => {
+                panic!("the extension answered")
+            }
+            _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {}
+        }
+
+        let unregistered = tokio::time::timeout(std::time::Duration::from_secs(5), registry.unregister(&id)).await;
+        assert!(matches!(unregistered, Ok(Ok(()))));
+        assert_eq!(Arc::strong_count(&registry.shared), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        // Generous limits, so a slow machine still counts as healthy
+        let check = HealthCheck::new()
+            .interval(std::time::Duration::from_millis(50))
+            .timeout(std::time::Duration::from_secs(60))
+            .slow(std::time::Duration::from_secs(60));
+        let registry = Registry::new().with_health_checks(check);
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        let status = loop {
+            if let Some(status) = registry.health(&id)
+                && status.last_probe.is_some()
+            {
+                break status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        assert_eq!(status.health, Health::Healthy);
+        assert_eq!(registry.health_all().len(), 1);
+
+        registry.unregister(&id).await.unwrap();
+        assert!(registry.health(&id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_restarts_unresponsive() {
+        let check = HealthCheck::new()
+            .interval(Duration::from_millis(50))
+            .timeout(Duration::from_millis(50))
+            .unresponsive_after(2)
+            .restart(true);
+        let mut registry = Registry::new().with_health_checks(check);
+        let id = "spin".to_string();
+        registry.register(id.clone(), fixture("spin").await).await.unwrap();
+
+        // The command never finishes, and the extension keeps working on it after the caller gives up, so every
+        // probe waits behind it until it times out
+        let command = Command::ExecuteTool {
+            tool_id: "spin".to_string(),
+            params: serde_json::json!({}),
+        };
+        tokio::select! {
+            _ = registry.call(&id, command) => panic!("the extension answered"),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+
+        let restarted = tokio::time::timeout(Duration::from_secs(60), async {
+            let mut lifecycle = registry.lifecycle();
+            while let Some((_, event)) = lifecycle.next().await {
+                if matches!(event, Lifecycle::Restarted) {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(restarted.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_broadcast() {
+        let registry = Registry::new();
+        registry.register("kv".to_string(), kv().await).await.unwrap();
+        registry.register("kv-2".to_string(), kv().await).await.unwrap();
+        registry.register("other".to_string(), kv().await).await.unwrap();
+
+        let ready = Broadcast::new(Command::View).timeout(std::time::Duration::from_secs(120));
+        let results = registry.broadcast(ready.clone().matching("kv*")).await;
+        assert_eq!(results.keys().collect::<Vec<_>>(), vec!["kv", "kv-2"]);
+        assert!(results.values().all(|r| matches!(r, Ok(Response::Data(_)))));
+
+        // No manifest, so no capabilities
+        let results = registry.broadcast(ready.capability("network")).await;
+        assert!(results.is_empty());
+
+        // Still loading, so it cannot answer in time
+        registry.register("late".to_string(), kv().await).await.unwrap();
+        let broadcast = Broadcast::new(Command::View).timeout(std::time::Duration::from_millis(1));
+        let results = registry.broadcast(broadcast).await;
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results["late"], Err(Error::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_peer_calls() {
+        let registry = Registry::new();
+        let kv_id = "kv".to_string();
+        let caller = "strategy".to_string();
+        registry.register(kv_id.clone(), kv().await).await.unwrap();
+        let peers = registry.peers(&caller);
+
+        // The KV guest answers every emporium command with an error, which is passed back to the caller
+        let error = peers
+            .execute(&caller, &kv_id, "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.contains("Invalid message"));
+        assert!(registry.shared.waiting.lock().unwrap().is_empty());
+
+        // If kv were waiting on the caller, neither could answer the other. Its instances can each be waiting, and
+        // the cycle lasts until all of them are done.
+        let first = Waiting::start(&registry.shared, &kv_id, &caller).unwrap();
+        let second = Waiting::start(&registry.shared, &kv_id, &caller).unwrap();
+        drop(first);
+        let error = peers
+            .execute(&caller, &kv_id, "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert_eq!(error, "Calling kv would deadlock: strategy -> kv -> strategy");
+        drop(second);
+        assert!(registry.shared.waiting.lock().unwrap().is_empty());
+
+        let error = peers
+            .execute(&caller, &"missing".to_string(), "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.contains("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let registry = Registry::new();
+        let build = concat!(env!("CARGO_MANIFEST_DIR"), "/marketplace/build/emporium_kv");
+        let (path, mut manifest) = crate::list(build).pin().next().await.unwrap();
+        manifest.subscriptions = vec!["quotes.*".to_string()];
+
+        let audit = crate::audit::Audit::new();
+        let kv = crate::load(manifest.id.clone(), String::new(), path)
+            .await
+            .unwrap()
+            .with_manifest(manifest)
+            .with_audit(audit.clone());
+        registry.register("kv".to_string(), kv).await.unwrap();
+
+        let mut quotes = registry.subscribe("quotes.*");
+        let payload = serde_json::json!({ "price": 227.5 });
+        assert_eq!(registry.publish("quotes.AAPL", payload.clone()), 2);
+        assert_eq!(registry.publish("news.AAPL", payload.clone()), 0);
+
+        let message = quotes.next().await.unwrap();
+        assert_eq!(
+            (message.topic.as_str(), message.source, message.payload),
+            ("quotes.AAPL", None, payload)
+        );
+
+        // Commands are processed in order, so the event has been delivered once this returns
+        registry.call(&"kv".to_string(), Command::ListTools).await.unwrap();
+        let commands = audit.query(&crate::audit::Query::new().kind("command"));
+        let delivered = commands.iter().any(|record| {
+            matches!(&record.event, crate::audit::Event::Command { command }
+                if command.starts_with(r#"{"type":"Event","payload":{"topic":"quotes.AAPL""#))
+        });
+        assert!(delivered);
+
+        drop(quotes);
+        assert_eq!(registry.publish("quotes.MSFT", serde_json::json!({})), 1);
+    }
+
+    #[tokio::test]
+    async fn test_from_config() {
+        let dir = std::env::temp_dir().join(format!("emporium-host-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("host.toml");
+        std::fs::write(
+            &config,
+            r#"
+            [extensions.kv]
+            config = { store = "memory" }
+
+            [extensions.alphavantage]
+            enabled = false
+
+            [extensions.polygon]
+            config = { api_key = "${EMPORIUM_TEST_UNSET}" }
+
+            [extensions.missing]
+
+            # The marketplace builds are not signed
+            [trust]
+            policy = "warn"
+            "#,
+        )
+        .unwrap();
+
+        let build = concat!(env!("CARGO_MANIFEST_DIR"), "/marketplace/build");
+        let (registry, report) = Registry::from_config(&config, [build]).await.unwrap();
+
+        assert_eq!(registry.list_extensions(), vec!["kv".to_string()]);
+        assert_eq!(report.loaded.len(), 1);
+        assert_eq!(report.disabled, vec!["alphavantage".to_string()]);
+
+        let mut failed: Vec<&str> = report.failed.iter().filter_map(|f| f.id.as_deref()).collect();
+        failed.sort();
+        assert_eq!(failed, vec!["missing", "polygon"]);
+        assert!(!report.is_ok());
+
+        // Unsigned components are refused unless the config says to only warn
+        std::fs::write(&config, "[extensions.polygon]\nenabled = false\n").unwrap();
+        let (registry, report) = Registry::from_config(&config, [build]).await.unwrap();
+        assert!(registry.list_extensions().is_empty());
+        let kv = report.failed.iter().find(|f| f.id.as_deref() == Some("kv")).unwrap();
+        assert!(matches!(kv.error, Error::SignatureError(SignatureError::Unsigned(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_all_verifies_signatures() {
+        let build = concat!(env!("CARGO_MANIFEST_DIR"), "/marketplace/build/emporium_kv");
+        let dir = std::env::temp_dir().join(format!("emporium-signed-{}", std::process::id()));
+        let kv = dir.join("emporium_kv");
+        std::fs::create_dir_all(&kv).unwrap();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let wasm = std::fs::read(format!("{}/emporium_kv.wasm", build)).unwrap();
+        let signature = Signature::sign("inboard", &key, "kv", "0.1.0", &wasm);
+        let manifest = std::fs::read_to_string(format!("{}/manifest.toml", build)).unwrap();
+        std::fs::write(
+            kv.join("manifest.toml"),
+            format!(
+                "{}\n[signature]\nsha256 = \"{}\"\npublisher = \"{}\"\ned25519 = \"{}\"\n",
+                manifest, signature.sha256, signature.publisher, signature.ed25519
+            ),
+        )
+        .unwrap();
+
+        let host = HostConfig::parse(&format!(
+            "[trust]\npublishers = {{ inboard = \"{}\" }}",
+            hex::encode(key.verifying_key().to_bytes())
+        ))
+        .unwrap();
+        let trust = host.trust().unwrap();
+
+        let mut tampered = wasm.clone();
+        tampered.extend_from_slice(b"\0");
+        std::fs::write(kv.join("emporium_kv.wasm"), &tampered).unwrap();
+        let registry = Registry::new();
+        let report = registry.load_all(&host, &trust, [&dir]).await;
+        assert!(registry.list_extensions().is_empty());
+        assert_eq!(report.failed.len(), 1);
+        assert!(matches!(
+            report.failed[0].error,
+            Error::SignatureError(SignatureError::Tampered(_))
+        ));
+
+        std::fs::write(kv.join("emporium_kv.wasm"), &wasm).unwrap();
+        let report = registry.load_all(&host, &trust, [&dir]).await;
+        assert!(report.is_ok());
+        assert_eq!(registry.list_extensions(), vec!["kv".to_string()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tool_catalog() {
+        let registry = Registry::new();
+        let id = "polygon".to_string();
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/marketplace/build/xt-polygon/extension.wasm"
+        );
+        let config = serde_json::json!({ "api_key": "test" }).to_string();
+        let polygon = crate::load(id.clone(), config, path.into()).await.unwrap();
+        registry.register(id.clone(), polygon).await.unwrap();
+        registry.register("kv".to_string(), kv().await).await.unwrap();
+
+        // The catalog request was queued first, so it has been answered once this returns
+        let Response::ToolList(listed) = registry.call(&id, Command::ListTools).await.unwrap() else {
+            panic!("Expected a tool list");
+        };
+        registry.call(&"kv".to_string(), Command::ListTools).await.unwrap();
+
+        let tools = registry.tools();
+        assert!(!tools.is_empty());
+        assert_eq!(tools.len(), listed.len());
+        assert!(tools.iter().all(|tool| tool.id.starts_with("polygon.")));
+
+        assert!(matches!(
+            registry.execute("kv.get", serde_json::json!({})).await,
+            Err(Error::RegistryNotFound(_))
+        ));
+
+        registry.unregister(&id).await.unwrap();
+        assert!(registry.tools().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peak_memory_across_instances() {
+        let registry = Registry::new();
+        registry
+            .register("one".to_string(), fixture("echo").await)
+            .await
+            .unwrap();
+        registry
+            .register("three".to_string(), fixture("echo").await.with_instances(3))
+            .await
+            .unwrap();
+        registry.call(&"one".to_string(), Command::ListTools).await.unwrap();
+        registry.call(&"three".to_string(), Command::ListTools).await.unwrap();
+
+        // Each instance has its own memory, and they add up
+        let metrics = registry.metrics();
+        let one = metrics.extensions["one"].peak_memory;
+        assert!(one > 0);
+        assert_eq!(metrics.extensions["three"].peak_memory, 3 * one);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let registry = Registry::new().with_fuel_metering();
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        let command = Command::ExecuteTool {
+            tool_id: "get".to_string(),
+            params: serde_json::json!({}),
+        };
+        let response = registry.call(&id, command).await.unwrap();
+        assert!(matches!(response, Response::Error(_)));
+
+        let metrics = registry.metrics();
+        let kv = &metrics.extensions[&id];
+        // The catalog request, then the tool call
+        assert_eq!((kv.calls.commands, kv.calls.errors), (2, 2));
+        assert_eq!(kv.tools["get"].errors, 1);
+        assert!(kv.calls.fuel > 0);
+        assert!(kv.peak_memory > 0);
+        assert!(
+            metrics
+                .prometheus()
+                .contains("emporium_tool_commands_total{extension=\"kv\",tool=\"get\"} 1\n")
+        );
+
+        registry.restart(&id).unwrap();
+        registry.unregister(&id).await.unwrap();
+        assert_eq!(registry.metrics().extensions[&id].restarts, 1);
+
+        // Fuel is only metered on request
+        let unmetered = Registry::new();
+        unmetered.register(id.clone(), self::kv().await).await.unwrap();
+        unmetered.call(&id, Command::ListTools).await.unwrap();
+        assert_eq!(unmetered.metrics().extensions[&id].calls.fuel, 0);
+    }
+
+    #[tokio::test]
+    async fn test_restore_state() {
+        let dir = std::env::temp_dir().join(format!("emporium-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let registry = Registry::new();
+        let config = serde_json::json!({ "store": "memory", "token": "hunter2" }).to_string();
+        let extension = kv()
+            .await
+            .with_config(config)
+            .with_sensitive_field("token")
+            .with_dir(&dir, "/data");
+        registry.register("kv".to_string(), extension).await.unwrap();
+
+        let mut state = registry.state();
+        assert_eq!(state.extensions.len(), 1);
+        assert_eq!(state.extensions[0].config, serde_json::json!({ "store": "memory" }));
+        assert_eq!(state.extensions[0].dirs, vec![(dir.clone(), "/data".to_string())]);
+
+        let polygon = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/marketplace/build/xt-polygon/extension.wasm"
+        );
+        state.extensions.push(crate::state::Saved {
+            id: "polygon".to_string(),
+            version: Some("2.0.0".to_string()),
+            path: polygon.into(),
+            config: serde_json::json!({}),
+            sensitive: Vec::new(),
+            dirs: Vec::new(),
+        });
+        state.extensions.push(crate::state::Saved {
+            id: "missing".to_string(),
+            version: None,
+            path: dir.join("missing.wasm"),
+            config: serde_json::Value::Null,
+            sensitive: Vec::new(),
+            dirs: Vec::new(),
+        });
+
+        let path = dir.join("state.json");
+        state.save(&path).unwrap();
+        let state = State::load(&path).unwrap();
+        drop(registry);
+
+        let host = HostConfig::parse(
+            r#"
+            [extensions.kv]
+            config = { store = "disk", token = "restored" }
+            "#,
+        )
+        .unwrap();
+        let registry = Registry::new();
+        // Registered without a manifest, so it cannot be signed
+        let refused = registry.restore(&state, &host, &host.trust().unwrap()).await;
+        let kv = refused.failed.iter().find(|f| f.id.as_deref() == Some("kv")).unwrap();
+        assert!(matches!(kv.error, Error::SignatureError(SignatureError::Unsigned(_))));
+        let report = registry.restore(&state, &host, &Trust::new(Policy::Warn)).await;
+
+        assert_eq!(registry.list_extensions(), vec!["kv".to_string()]);
+        let saved = &registry.state().extensions[0];
+        assert_eq!(saved.config, serde_json::json!({ "store": "memory" }));
+        assert_eq!(saved.dirs.len(), 1);
+
+        let failed: Vec<&str> = report.failed.iter().filter_map(|f| f.id.as_deref()).collect();
+        assert_eq!(failed, vec!["polygon", "missing"]);
+        assert!(matches!(report.failed[0].error, Error::StateError(_)));
+        assert!(matches!(report.failed[1].error, Error::ExtensionNotFound(_)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let registry = Registry::new();
+        let extension = kv().await;
+
+        let alice = registry.tenant("alice");
+        alice.set_quota(crate::tenant::Quota::new().extensions(1));
+        alice.register("kv".to_string(), extension.clone()).await.unwrap();
+        assert!(matches!(
+            alice.register("other".to_string(), extension.clone()).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        let bob = registry.tenant("bob");
+        bob.register("kv".to_string(), extension).await.unwrap();
+
+        let mut ids = registry.list_extensions();
+        ids.sort();
+        assert_eq!(ids, vec!["alice/kv".to_string(), "bob/kv".to_string()]);
+        assert_eq!(alice.list_extensions(), vec!["kv".to_string()]);
+        assert_eq!(registry.tenants(), vec!["alice".to_string(), "bob".to_string()]);
+
+        assert!(matches!(alice.call("kv", Command::View).await, Ok(Response::Data(_))));
+        bob.set_quota(crate::tenant::Quota::new().calls(0));
+        assert!(matches!(
+            bob.call("kv", Command::View).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        // The quotas hold for the tenant's extensions whichever way they are reached
+        assert!(matches!(
+            registry.call(&bob.id("kv"), Command::View).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+        let error = registry
+            .peers(&bob.id("strategy"))
+            .execute(&"strategy".to_string(), &"kv".to_string(), "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.contains("at most 0 calls"));
+        assert!(matches!(
+            registry.register(alice.id("other"), kv().await).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        // Only one of two concurrent registrations fits
+        let carol = registry.tenant("carol");
+        carol.set_quota(crate::tenant::Quota::new().extensions(1));
+        let (a, b) = tokio::join!(
+            carol.register("a".to_string(), kv().await),
+            carol.register("b".to_string(), kv().await)
+        );
+        assert!(a.is_ok() != b.is_ok());
+        carol.teardown().await;
+
+        // Extensions of a tenant call the tenant's own instances
+        let caller = "strategy".to_string();
+        let peers = registry.peers(&alice.id(&caller));
+        let error = peers
+            .execute(&caller, &"kv".to_string(), "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.contains("Invalid message"));
+        let error = peers
+            .execute(&caller, &"missing".to_string(), "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.ends_with("Extension alice/missing not found"));
+
+        alice.teardown().await;
+        assert_eq!(registry.list_extensions(), vec!["bob/kv".to_string()]);
+
+        // Dependencies resolve against the tenant's own extensions
+        let manifest = crate::Manifest::parse(include_str!("../marketplace/build/emporium_kv/manifest.toml")).unwrap();
+        let mut strategy = manifest.clone();
+        strategy.id = "strategy".to_string();
+        strategy
+            .dependencies
+            .extensions
+            .insert("kv".to_string(), "^0.1".to_string());
+        let carol = registry.tenant("carol");
+        carol
+            .register("kv".to_string(), kv().await.with_manifest(manifest))
+            .await
+            .unwrap();
+        carol
+            .register("strategy".to_string(), kv().await.with_manifest(strategy.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            registry
+                .tenant("dave")
+                .register("strategy".to_string(), kv().await.with_manifest(strategy))
+                .await,
+            Err(Error::DependencyError(crate::error::DependencyError::Missing(..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_posted_middleware_waits() {
+        /// Holds commands back until it opens, keeping only the first waker it is polled with
+        #[derive(Default)]
+        struct Gate(Mutex<(bool, Option<std::task::Waker>)>);
+
+        struct Held(Arc<Gate>);
+
+        impl Middleware for Held {
+            fn handle<'a>(&'a self, call: Call, next: Next<'a>) -> Reply<'a> {
+                Box::pin(async move {
+                    std::future::poll_fn(|context| {
+                        let mut gate = self.0.0.lock().unwrap();
+                        if gate.0 {
+                            return std::task::Poll::Ready(());
+                        }
+                        gate.1.get_or_insert_with(|| context.waker().clone());
+                        std::task::Poll::Pending
+                    })
+                    .await;
+                    next.run(call).await
+                })
+            }
+        }
+
+        let gate = Arc::new(Gate::default());
+        let mut registry = Registry::new().with_middleware(Held(gate.clone()));
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        registry.send_message(&id, Command::View).unwrap();
+        let early = tokio::time::timeout(Duration::from_millis(50), registry.events().next()).await;
+        assert!(early.is_err());
+
+        // Opening the gate wakes the dispatch with the waker of its first run, before it moved to a task
+        let waker = {
+            let mut gate = gate.0.lock().unwrap();
+            gate.0 = true;
+            gate.1.take()
+        };
+        waker.unwrap().wake();
+        let (_, response) = tokio::time::timeout(Duration::from_secs(60), registry.events().next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(response, Response::Data(_)));
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        use crate::middleware::{Call, Middleware, Next, Reply};
+
+        /// Records every call and whether it was answered
+        struct Log(Arc<Mutex<Vec<String>>>);
+
+        impl Middleware for Log {
+            fn handle<'a>(&'a self, call: Call, next: Next<'a>) -> Reply<'a> {
+                Box::pin(async move {
+                    let entry = format!("{} from {:?}", call.extension_id, call.caller);
+                    let response = next.run(call).await;
+                    self.0.lock().unwrap().push(format!("{}: {}", entry, response.is_ok()));
+                    response
+                })
+            }
+        }
+
+        /// Refuses one tool and rewrites a custom command
+        struct Guard;
+
+        impl Middleware for Guard {
+            fn handle<'a>(&'a self, mut call: Call, next: Next<'a>) -> Reply<'a> {
+                match &call.command {
+                    Command::ExecuteTool { tool_id, .. } if tool_id == "forbidden" => {
+                        Box::pin(async { Ok(Response::Error("Forbidden".to_string())) })
+                    }
+                    Command::Custom(name) if name == "view" => {
+                        call.command = Command::View;
+                        next.run(call)
+                    }
+                    _ => next.run(call),
+                }
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = Registry::new().with_middleware(Log(log.clone())).with_middleware(Guard);
+        let id = "kv".to_string();
+        registry.register(id.clone(), kv().await).await.unwrap();
+
+        let response = registry.call(&id, Command::Custom("view".to_string())).await.unwrap();
+        assert!(matches!(response, Response::Data(_)));
+
+        let forbidden = Command::ExecuteTool {
+            tool_id: "forbidden".to_string(),
+            params: serde_json::json!({}),
+        };
+        let response = registry.call(&id, forbidden).await.unwrap();
+        assert!(matches!(response, Response::Error(error) if error == "Forbidden"));
+
+        let caller = "strategy".to_string();
+        registry
+            .peers(&caller)
+            .execute(&caller, &id, "get", serde_json::json!({}))
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "kv from None: true",
+                "kv from None: true",
+                "kv from Some(\"strategy\"): true",
+            ]
+        );
+
+        // Messages go through the middleware before their replies are published
+        registry.send_message(&id, Command::Custom("view".to_string())).unwrap();
+        let (_, response) = registry.events().next().await.unwrap();
+        assert!(matches!(response, Response::Data(_)));
+        assert_eq!(log.lock().unwrap().len(), 4);
+
+        // And so do events, whose replies are discarded
+        let build = concat!(env!("CARGO_MANIFEST_DIR"), "/marketplace/build/emporium_kv");
+        let (path, mut manifest) = crate::list(build).pin().next().await.unwrap();
+        manifest.subscriptions = vec!["quotes.*".to_string()];
+        let feed = crate::load(manifest.id.clone(), String::new(), path)
+            .await
+            .unwrap()
+            .with_manifest(manifest);
+        registry.register("feed".to_string(), feed).await.unwrap();
+
+        assert_eq!(registry.publish("quotes.AAPL", serde_json::json!({})), 1);
+        let logged = async {
+            while !log.lock().unwrap().iter().any(|entry| entry == "feed from None: true") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), logged).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_runs_on_instances() {
+        use crate::permission::{Decision, Permissions};
+
+        // Each call reads the `token` secret, and waits there until the permission prompt is answered
+        let (policy, mut prompts) = crate::permission::prompt();
+        let audit = crate::audit::Audit::new();
+        let extension = fixture("echo")
+            .await
+            .with_secret("token", "t0ken")
+            .with_permissions(Permissions::new(policy))
+            .with_audit(audit.clone())
+            .with_instances(4);
+        let registry = Registry::new();
+        let id = "echo".to_string();
+        registry.register(id.clone(), extension).await.unwrap();
+        registry.call(&id, Command::ListTools).await.unwrap();
+
+        let batch = (0..4).fold(Batch::new().concurrency(4), |batch, i| {
+            batch.call("echo.echo", serde_json::json!(i))
+        });
+        let calls = async {
+            let prompt = prompts.next().await.unwrap();
+            // Every call has reached the guest while the first is still waiting
+            let query = crate::audit::Query::new().extension(&id).kind("command");
+            let started = tokio::time::timeout(Duration::from_secs(60), async {
+                let calls = |records: Vec<crate::audit::Record>| {
+                    records
+                        .into_iter()
+                        .filter(|r| matches!(&r.event, crate::audit::Event::Command { command } if command.contains("ExecuteTool")))
+                        .count()
+                };
+                while calls(audit.query(&query)) < 4 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            assert!(started.is_ok());
+            prompt.answer(Decision::AllowOnce);
+        };
+        let (results, ()) = tokio::join!(registry.execute_batch(batch), calls);
+
+        for (i, result) in results.into_iter().enumerate() {
+            assert!(matches!(
+                result,
+                Ok(Response::ToolResult { result, .. }) if result["payload"]["params"] == serde_json::json!(i)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_audit() {
+        use crate::audit::{Audit, Event, Query};
+
+        let dir = std::env::temp_dir().join(format!("emporium-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit = Audit::new();
+        let extension = fixture("files").await.with_dir(&dir, "/data").with_audit(audit.clone());
+        let registry = Registry::new();
+        let id = "files".to_string();
+        registry.register(id.clone(), extension).await.unwrap();
+
+        let command = Command::ExecuteTool {
+            tool_id: "touch".to_string(),
+            params: serde_json::json!({}),
+        };
+        registry.call(&id, command).await.unwrap();
+        assert!(dir.join("note.txt").exists());
+        // The directory was preopened before the component started
+        assert!(dir.join("start.txt").exists());
+
+        // The directory it was given, then each file it opened
+        assert_eq!(audit.query(&Query::new().extension(&id).kind("filesystem")).len(), 1);
+        let opens: Vec<Event> = audit
+            .query(&Query::new().extension(&id).kind("open"))
+            .into_iter()
+            .map(|record| record.event)
+            .collect();
+        assert_eq!(opens.len(), 3);
+        assert_eq!(
+            opens[0],
+            Event::Open {
+                path: "start.txt".to_string(),
+                mode: "write,create".to_string(),
+                error: None,
+            }
+        );
+        assert_eq!(
+            opens[1],
+            Event::Open {
+                path: "note.txt".to_string(),
+                mode: "write,create".to_string(),
+                error: None,
+            }
+        );
+        assert!(matches!(
+            &opens[2],
+            Event::Open { path, mode, error: Some(_) } if path == "missing.txt" && mode == "read"
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tool_cache() {
+        let cache = Cache::memory();
+        let registry = Registry::new().with_tool_cache(cache.clone());
+        let id = "echo".to_string();
+        registry.register(id.clone(), fixture("echo").await).await.unwrap();
+        // The catalog request was queued first, so the tool is known to be cacheable once this returns
+        registry.call(&id, Command::ListTools).await.unwrap();
+
+        let params = serde_json::json!({ "key": "a" });
+        for _ in 0..2 {
+            let response = registry.execute("echo.echo", params.clone()).await.unwrap();
+            assert!(matches!(
+                response,
+                Response::ToolResult { tool_id, result } if tool_id == "echo.echo" && result["payload"]["params"] == params
+            ));
+        }
+        let stats = cache.stats(&id);
+        assert_eq!((stats.hits, stats.misses, stats.stores), (1, 1, 1));
+
+        // What the guest answered is what was stored
+        let stored = cache.get(&id, &Cache::key(&id, "", "echo", &params)).await.unwrap();
+        assert_eq!(stored["payload"]["params"], params);
+
+        registry.execute_fresh("echo.echo", params.clone()).await.unwrap();
+        let stats = cache.stats(&id);
+        assert_eq!((stats.hits, stats.misses, stats.stores), (2, 1, 2));
+
+        // A reloaded extension starts over
+        registry.reload(&id, fixture("echo").await).unwrap();
+        registry.call(&id, Command::ListTools).await.unwrap();
+        registry.execute("echo.echo", params).await.unwrap();
+        let stats = cache.stats(&id);
+        assert_eq!((stats.hits, stats.misses, stats.stores), (2, 2, 3));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_schedules() {
+        let mut registry = Registry::new();
+        let id = "echo".to_string();
+        registry.register(id.clone(), fixture("echo").await).await.unwrap();
+        registry.call(&id, Command::ListTools).await.unwrap();
+        let params = serde_json::json!({ "key": "a" });
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let schedule =
+            Schedule::every("echo.echo", params.clone(), Duration::from_millis(50)).on_result(move |id, result| {
+                let _ = tx.unbounded_send((id, result));
+            });
+        let scheduled = registry.schedule(schedule);
+
+        for _ in 0..2 {
+            let (id, result) = tokio::time::timeout(Duration::from_secs(5), rx.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(id, scheduled);
+            assert!(matches!(result, Ok(Response::ToolResult { result, .. }) if result["payload"]["params"] == params));
+        }
+
+        registry.pause_schedule(scheduled).unwrap();
+        assert!(registry.schedules()[&scheduled].paused);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        while rx.try_recv().is_ok() {}
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err(), "A paused schedule ran");
+
+        registry.resume_schedule(scheduled).unwrap();
+        let (_, result) = tokio::time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_ok());
+        assert!(!registry.schedules()[&scheduled].paused);
+
+        registry.cancel_schedule(scheduled).unwrap();
+        assert!(registry.cancel_schedule(scheduled).is_err());
+
+        // Without a callback, results are published as events of the extension
+        registry.schedule(Schedule::every("echo.echo", params, Duration::from_millis(50)));
+        let (source, response) = tokio::time::timeout(Duration::from_secs(5), registry.events().next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(source, id);
+        assert!(matches!(response, Response::ToolResult { tool_id, .. } if tool_id == "echo.echo"));
     }
 }
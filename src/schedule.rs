@@ -0,0 +1,406 @@
+//! Execute tools on a timer, see [`Registry::schedule`](crate::Registry::schedule).
+//!
+//! ```ignore
+//! // Every minute, up to five seconds late so that hosts do not all call at once
+//! let quotes = Schedule::every("polygon.call_endpoint", params, Duration::from_secs(60))
+//!     .jitter(Duration::from_secs(5));
+//! // At the US market close on weekdays (cron fields are in UTC)
+//! let daily = Schedule::cron("alphavantage.daily_series", params, "0 21 * * 1-5")?;
+//! ```
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::Error;
+use crate::data::Response;
+
+/// Identifies a schedule in its registry
+pub type ScheduleId = u64;
+
+/// Receives the result of every scheduled run
+pub type Callback = Arc<dyn Fn(ScheduleId, Result<Response, Error>) + Send + Sync>;
+
+/// What to do when a run could not happen on time, e.g. while the schedule was paused or the previous run was slow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Missed {
+    /// Wait for the next run that is still ahead
+    #[default]
+    Skip,
+    /// Run once right away for all the runs that were missed, then continue as usual
+    RunOnce,
+}
+
+/// When a scheduled tool runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Spec {
+    Interval(Duration),
+    Cron(Cron),
+}
+
+/// A tool to execute repeatedly, and where its results go
+#[derive(Clone)]
+pub struct Schedule {
+    pub(crate) tool: String,
+    pub(crate) params: Value,
+    spec: Spec,
+    jitter: Duration,
+    missed: Missed,
+    pub(crate) callback: Option<Callback>,
+}
+
+/// Where a schedule stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// The qualified id of the tool it executes
+    pub tool: String,
+    pub paused: bool,
+    /// When it runs next, before jitter, or `None` if it never will
+    pub next_run: Option<SystemTime>,
+    pub last_run: Option<SystemTime>,
+    pub runs: u64,
+}
+
+impl Schedule {
+    /// Execute the qualified `tool` with `params` every `interval`, starting one interval from now
+    pub fn every(tool: impl Into<String>, params: Value, interval: Duration) -> Self {
+        Self::new(
+            tool.into(),
+            params,
+            Spec::Interval(interval.max(Duration::from_millis(1))),
+        )
+    }
+
+    /// Execute the qualified `tool` with `params` whenever the cron `expression` matches, see [`Cron`]
+    pub fn cron(tool: impl Into<String>, params: Value, expression: &str) -> Result<Self, Error> {
+        Ok(Self::new(tool.into(), params, Spec::Cron(Cron::parse(expression)?)))
+    }
+
+    fn new(tool: String, params: Value, spec: Spec) -> Self {
+        Self {
+            tool,
+            params,
+            spec,
+            jitter: Duration::ZERO,
+            missed: Missed::default(),
+            callback: None,
+        }
+    }
+
+    /// Delay each run by a random amount up to `jitter`
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// What to do about runs that could not happen on time
+    pub fn missed(mut self, missed: Missed) -> Self {
+        self.missed = missed;
+        self
+    }
+
+    /// Pass results to `callback` instead of publishing them on [`Registry::events`](crate::Registry::events)
+    pub fn on_result(mut self, callback: impl Fn(ScheduleId, Result<Response, Error>) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// The first run due after `time`
+    fn next(&self, time: SystemTime) -> Option<SystemTime> {
+        match &self.spec {
+            Spec::Interval(interval) => Some(time + *interval),
+            Spec::Cron(cron) => cron.next_after(time),
+        }
+    }
+
+    fn jittered(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        let random = std::collections::hash_map::RandomState::new().hash_one(SystemTime::now());
+        self.jitter.mul_f64((random % 1_000_000) as f64 / 1_000_000.0)
+    }
+}
+
+impl std::fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Schedule")
+            .field("tool", &self.tool)
+            .field("spec", &self.spec)
+            .field("jitter", &self.jitter)
+            .field("missed", &self.missed)
+            .finish()
+    }
+}
+
+/// A running schedule. Dropping it stops the schedule.
+pub(crate) struct Control {
+    paused: watch::Sender<bool>,
+    status: Arc<Mutex<Status>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Control {
+    pub(crate) fn pause(&self, paused: bool) {
+        self.paused.send_replace(paused);
+        self.status.lock().unwrap().paused = paused;
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Start running `schedule`, calling `fire` for every run until it returns `false`. Must be called within a Tokio
+/// runtime.
+pub(crate) fn spawn<F, Fut>(schedule: Schedule, fire: F) -> Control
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    let (paused, mut paused_rx) = watch::channel(false);
+    let status = Arc::new(Mutex::new(Status {
+        tool: schedule.tool.clone(),
+        paused: false,
+        next_run: schedule.next(SystemTime::now()),
+        last_run: None,
+        runs: 0,
+    }));
+
+    let task = tokio::spawn({
+        let status = status.clone();
+        async move {
+            loop {
+                let Some(due) = status.lock().unwrap().next_run else {
+                    return;
+                };
+
+                if *paused_rx.borrow_and_update() {
+                    if paused_rx.changed().await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+
+                let jitter = schedule.jittered();
+                let wait = due.duration_since(SystemTime::now()).unwrap_or_default() + jitter;
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    changed = paused_rx.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                }
+
+                let now = SystemTime::now();
+                // Another run fell due in the meantime, so at least one was missed. The jitter is a delay the
+                // schedule asked for, so it does not count.
+                let woken = now.checked_sub(jitter).unwrap_or(now);
+                let late = schedule.next(due).is_some_and(|next| next <= woken);
+                if late && schedule.missed == Missed::Skip {
+                    status.lock().unwrap().next_run = schedule.next(now);
+                    continue;
+                }
+
+                if !fire().await {
+                    return;
+                }
+
+                let mut status = status.lock().unwrap();
+                status.runs += 1;
+                status.last_run = Some(now);
+                status.next_run = match schedule.next(due) {
+                    Some(next) if next <= SystemTime::now() => schedule.next(SystemTime::now()),
+                    next => next,
+                };
+            }
+        }
+    });
+
+    Control { paused, status, task }
+}
+
+/// A cron expression of five fields: minute, hour, day of month, month and day of week, matched in UTC.
+///
+/// Each field is `*`, a value, a range `a-b`, or a comma separated list of those, and any of them may be followed by
+/// a step such as `*/15`. Days of the week run from 0 for Sunday to 6, and 7 is Sunday too. As in cron, when both the
+/// day of month and the day of week are restricted, a day matching either is enough.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Whether the day of month and day of week fields are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, Error> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(Error::ConfigError(format!(
+                "Cron expression {:?} must have 5 fields, not {}",
+                expression,
+                fields.len()
+            )));
+        };
+
+        let field = |text: &str, min: usize, max: usize| {
+            field(text, min, max)
+                .map_err(|reason| Error::ConfigError(format!("Invalid cron expression {:?}: {}", expression, reason)))
+        };
+
+        let mut weekdays_set = field(weekdays, 0, 7)?;
+        // 7 is another name for Sunday
+        weekdays_set[0] |= weekdays_set[7];
+        weekdays_set.truncate(7);
+
+        Ok(Self {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekdays_set,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    /// The first whole minute after `time` that matches, within the next eight years
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut minute = seconds / 60 + 1;
+        let last = minute + 8 * 366 * 24 * 60;
+
+        while minute < last {
+            let day = minute / (24 * 60);
+            if !self.matches_day(day) {
+                minute = (day + 1) * 24 * 60;
+                continue;
+            }
+
+            let (hour, of_hour) = ((minute / 60 % 24) as usize, (minute % 60) as usize);
+            if self.hours[hour] && self.minutes[of_hour] {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+            }
+            minute += 1;
+        }
+
+        None
+    }
+
+    /// Whether the day `day` days after the Unix epoch matches
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, of_month) = civil(day);
+        // The epoch was a Thursday
+        let weekday = ((day + 4) % 7) as usize;
+
+        let by_month = self.days[of_month as usize];
+        let by_week = self.weekdays[weekday];
+        let by_day = match (self.any_day, self.any_weekday) {
+            (false, false) => by_month || by_week,
+            _ => by_month && by_week,
+        };
+
+        self.months[month as usize] && by_day
+    }
+}
+
+/// Parse one cron field into a table indexed by value, with values below `min` never set
+fn field(text: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut set = vec![false; max + 1];
+    let number = |text: &str| {
+        text.parse::<usize>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("{:?} is not a number from {} to {}", text, min, max))
+    };
+
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|s| *s > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(|| format!("Invalid step in {:?}", part))?;
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // `5/10` means from 5 on, in steps of 10
+                None if part.contains('/') => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("Empty range {:?}", range));
+        }
+
+        for value in (start..=end).step_by(step) {
+            set[value] = true;
+        }
+    }
+
+    Ok(set)
+}
+
+/// The year, month and day of the day `day` days after the Unix epoch
+fn civil(day: u64) -> (i64, u32, u32) {
+    // Howard Hinnant's days_from_civil, inverted
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_next_after() {
+        let at = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+        // Friday 2024-01-05 22:00 UTC
+        let friday_night = at(1_704_492_000);
+
+        let close = Cron::parse("0 21 * * 1-5").unwrap();
+        // Monday 2024-01-08 21:00 UTC
+        assert_eq!(close.next_after(friday_night), Some(at(1_704_747_600)));
+
+        let quarterly = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(
+            quarterly.next_after(at(1_704_492_000 + 7 * 60)),
+            Some(at(1_704_492_000 + 15 * 60))
+        );
+
+        let leap_day = Cron::parse("30 12 29 2 *").unwrap();
+        // Thursday 2024-02-29 12:30 UTC
+        assert_eq!(leap_day.next_after(friday_night), Some(at(1_709_209_800)));
+
+        assert!(Cron::parse("61 * * * *").is_err());
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(friday_night), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! Verify that extension components come from a trusted publisher.
+use std::collections::HashMap;
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::error::SignatureError;
+use crate::extension::Manifest;
+
+/// The `[signature]` section of a manifest.
+///
+/// `ed25519` is the publisher's signature over the extension id, its version and the SHA-256 digest of the component,
+/// see [`Signature::message`]. Both are hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub sha256: String,
+    pub publisher: String,
+    pub ed25519: String,
+}
+
+/// What to do when a component fails verification.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Refuse to load the component
+    #[default]
+    Enforce,
+    /// Print a warning and load the component anyway
+    Warn,
+}
+
+/// The set of publisher keys the host trusts.
+#[derive(Debug, Clone, Default)]
+pub struct Trust {
+    publishers: HashMap<String, VerifyingKey>,
+    policy: Policy,
+}
+
+impl Signature {
+    /// Sign the component of version `version` of extension `id` on behalf of `publisher`
+    pub fn sign(publisher: impl Into<String>, key: &SigningKey, id: &str, version: &str, wasm: &[u8]) -> Self {
+        let sha256 = hex::encode(Sha256::digest(wasm));
+        let message = Self::message(id, version, &sha256);
+
+        Self {
+            ed25519: hex::encode(key.sign(&message).to_bytes()),
+            sha256,
+            publisher: publisher.into(),
+        }
+    }
+
+    /// What is signed: the id, version and hex encoded digest, each on its own line, so a signed component cannot
+    /// be shipped as another extension or version
+    pub fn message(id: &str, version: &str, sha256: &str) -> Vec<u8> {
+        format!("{}\n{}\n{}", id, version, sha256.to_ascii_lowercase()).into_bytes()
+    }
+}
+
+impl Trust {
+    /// Create an empty trust store with the given policy
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            publishers: HashMap::new(),
+            policy,
+        }
+    }
+
+    /// Trust `publisher` to sign with `key`
+    pub fn with_key(mut self, publisher: impl Into<String>, key: VerifyingKey) -> Self {
+        self.publishers.insert(publisher.into(), key);
+        self
+    }
+
+    /// Trust `publisher` to sign with the hex encoded ed25519 public key `key`
+    pub fn with_hex_key(self, publisher: impl Into<String>, key: &str) -> Result<Self, Error> {
+        let publisher = publisher.into();
+        let invalid = |reason: String| Error::from(SignatureError::InvalidKey(publisher.clone(), reason));
+
+        let bytes: [u8; 32] = hex::decode(key)
+            .map_err(|e| invalid(e.to_string()))?
+            .try_into()
+            .map_err(|_| invalid("expected 32 bytes".to_string()))?;
+        let key = VerifyingKey::from_bytes(&bytes).map_err(|e| invalid(e.to_string()))?;
+
+        Ok(self.with_key(publisher, key))
+    }
+
+    /// The active policy
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Check `wasm` against the signature in `manifest`.
+    ///
+    /// Under [`Policy::Warn`] failures are reported on stderr and `Ok` is returned.
+    pub fn verify(&self, manifest: &Manifest, wasm: &[u8]) -> Result<(), Error> {
+        self.enforce(self.check(manifest, wasm))
+    }
+
+    /// Check a component loaded without a manifest, which cannot carry a signature
+    pub fn verify_unsigned(&self, id: &str) -> Result<(), Error> {
+        self.enforce(Err(SignatureError::Unsigned(id.to_string())))
+    }
+
+    fn enforce(&self, checked: Result<(), SignatureError>) -> Result<(), Error> {
+        match (checked, self.policy) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), Policy::Warn) => {
+                eprintln!("Warning: {}", e);
+                Ok(())
+            }
+            (Err(e), Policy::Enforce) => Err(e.into()),
+        }
+    }
+
+    fn check(&self, manifest: &Manifest, wasm: &[u8]) -> Result<(), SignatureError> {
+        let id = &manifest.id;
+        let signature = manifest
+            .signature
+            .as_ref()
+            .ok_or_else(|| SignatureError::Unsigned(id.clone()))?;
+
+        let digest = Sha256::digest(wasm);
+        if !hex::encode(digest).eq_ignore_ascii_case(&signature.sha256) {
+            return Err(SignatureError::Tampered(id.clone()));
+        }
+
+        let key = self
+            .publishers
+            .get(&signature.publisher)
+            .ok_or_else(|| SignatureError::Untrusted(id.clone(), signature.publisher.clone()))?;
+
+        let invalid = |reason: String| SignatureError::Invalid(id.clone(), reason);
+        let bytes: [u8; 64] = hex::decode(&signature.ed25519)
+            .map_err(|e| invalid(e.to_string()))?
+            .try_into()
+            .map_err(|_| invalid("expected 64 bytes".to_string()))?;
+
+        let message = Signature::message(id, &manifest.version, &signature.sha256);
+        key.verify(&message, &ed25519_dalek::Signature::from_bytes(&bytes))
+            .map_err(|e| invalid(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(signature: Option<Signature>) -> Manifest {
+        Manifest {
+            id: "kv".to_string(),
+            name: "Key-Value Store".to_string(),
+            version: "0.1.0".to_string(),
+            component_entry: "emporium_kv.wasm".to_string(),
+            signature,
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let wasm = b"\0asm component";
+        let signed = manifest(Some(Signature::sign("inboard", &key, "kv", "0.1.0", wasm)));
+        let trust = Trust::new(Policy::Enforce).with_key("inboard", key.verifying_key());
+
+        assert!(trust.verify(&signed, wasm).is_ok());
+        assert!(matches!(
+            trust.check(&signed, b"\0asm tampered"),
+            Err(SignatureError::Tampered(_))
+        ));
+        assert!(matches!(
+            trust.check(&manifest(None), wasm),
+            Err(SignatureError::Unsigned(_))
+        ));
+        assert!(matches!(
+            Trust::default().check(&signed, wasm),
+            Err(SignatureError::Untrusted(_, _))
+        ));
+        assert!(Trust::new(Policy::Warn).verify(&manifest(None), wasm).is_ok());
+
+        // The same signed component shipped as another extension, or another version
+        let mut renamed = signed.clone();
+        renamed.id = "polygon".to_string();
+        assert!(matches!(trust.check(&renamed, wasm), Err(SignatureError::Invalid(..))));
+        let mut bumped = signed;
+        bumped.version = "0.2.0".to_string();
+        assert!(matches!(trust.check(&bumped, wasm), Err(SignatureError::Invalid(..))));
+    }
+}
@@ -0,0 +1,94 @@
+//! Save which extensions a registry runs, and how, so the same setup can be restored when the host starts again.
+//!
+//! Config fields marked sensitive and secrets are never written. On [restore](crate::Registry::restore) they come
+//! from the host config instead.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Error;
+use crate::data::Id;
+
+/// The version of the state file format this host writes and reads
+pub const FORMAT: u32 = 1;
+
+/// Everything a registry needs to load the same extensions again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub format: u32,
+    /// In the order they were registered
+    pub extensions: Vec<Saved>,
+}
+
+/// One registered extension
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Saved {
+    pub id: Id,
+    /// The manifest version, for extensions loaded with a manifest
+    pub version: Option<String>,
+    /// The component file
+    pub path: PathBuf,
+    /// The config, without sensitive fields
+    pub config: Value,
+    /// Config fields the host marked sensitive, whose values are left out
+    #[serde(default)]
+    pub sensitive: Vec<String>,
+    /// Host directories the extension has access to, and where it sees them
+    #[serde(default)]
+    pub dirs: Vec<(PathBuf, String)>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            format: FORMAT,
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl State {
+    /// Read the state file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let state: Self =
+            serde_json::from_str(&content).map_err(|e| Error::StateError(format!("{}: {}", path.display(), e)))?;
+
+        if state.format > FORMAT {
+            return Err(Error::StateError(format!(
+                "{} was written in format {}, but this host reads up to {}",
+                path.display(),
+                state.format,
+                FORMAT
+            )));
+        }
+        Ok(state)
+    }
+
+    /// Write the state file at `path`, replacing it only once the new one is complete
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self).map_err(|e| Error::StateError(e.to_string()))?;
+
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+impl Saved {
+    /// The config to load the extension with: the host's `config`, with the saved fields on top
+    pub(crate) fn config(&self, config: Value) -> Value {
+        match (config, &self.config) {
+            (Value::Object(mut fields), Value::Object(saved)) => {
+                fields.extend(saved.clone());
+                Value::Object(fields)
+            }
+            (config, Value::Null) => config,
+            (_, saved) => saved.clone(),
+        }
+    }
+}
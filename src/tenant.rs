@@ -0,0 +1,167 @@
+//! Run separate instances of the same extensions for each tenant of a shared host.
+//!
+//! A tenant's extensions are registered as `tenant/id`, so the rest of the [`Registry`] API sees them under that
+//! id. Tool calls between extensions and events they publish stay within their tenant.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::data::Id;
+use crate::{Command, Error, Extension, Registry, Response};
+
+/// Separates the tenant from the extension id in the registry
+pub const SEPARATOR: char = '/';
+
+/// Limits on what one tenant may use
+#[derive(Debug, Clone, Default)]
+pub struct Quota {
+    extensions: Option<usize>,
+    calls: Option<usize>,
+}
+
+impl Quota {
+    /// No limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// At most `extensions` registered at once
+    pub fn extensions(mut self, extensions: usize) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// At most `calls` commands waiting for an answer at once
+    pub fn calls(mut self, calls: usize) -> Self {
+        self.calls = Some(calls);
+        self
+    }
+}
+
+/// A tenant's quota and what it uses of it
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    quota: Mutex<Quota>,
+    calls: AtomicUsize,
+}
+
+impl Usage {
+    /// Refuse another extension for `tenant`, which has `registered` already, if that goes over its quota
+    pub(crate) fn check_extensions(&self, tenant: &str, registered: usize) -> Result<(), Error> {
+        match self.quota.lock().unwrap().extensions {
+            Some(limit) if registered >= limit => Err(Error::QuotaExceeded(format!(
+                "Tenant {} may register at most {} extensions",
+                tenant, limit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a call for `tenant` until the returned guard is dropped, unless it goes over its quota
+    pub(crate) fn start_call(self: Arc<Self>, tenant: &str) -> Result<Call, Error> {
+        let limit = self.quota.lock().unwrap().calls;
+        let calls = self.calls.fetch_add(1, Ordering::SeqCst);
+        let call = Call(self);
+
+        match limit {
+            Some(limit) if calls >= limit => Err(Error::QuotaExceeded(format!(
+                "Tenant {} may have at most {} calls in flight",
+                tenant, limit
+            ))),
+            _ => Ok(call),
+        }
+    }
+}
+
+/// The extensions of one tenant of a registry, see [`Registry::tenant`].
+///
+/// Its quota applies to everything done with its extensions, whether through this or through the registry with
+/// their scoped ids, including tool calls between them.
+pub struct Tenant<'a> {
+    registry: &'a Registry,
+    name: String,
+    usage: Arc<Usage>,
+}
+
+/// The registry id of the extension `id` of `tenant`
+pub fn scoped(tenant: &str, id: &str) -> Id {
+    format!("{}{}{}", tenant, SEPARATOR, id)
+}
+
+/// The tenant of a registry id, if it belongs to one
+pub fn of(id: &str) -> Option<&str> {
+    id.split_once(SEPARATOR).map(|(tenant, _)| tenant)
+}
+
+impl<'a> Tenant<'a> {
+    pub(crate) fn new(registry: &'a Registry, name: String, usage: Arc<Usage>) -> Self {
+        Self { registry, name, usage }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The id the tenant's extension `extension_id` has in the registry
+    pub fn id(&self, extension_id: &str) -> Id {
+        scoped(&self.name, extension_id)
+    }
+
+    /// Limit what the tenant may use from now on
+    pub fn set_quota(&self, quota: Quota) {
+        *self.usage.quota.lock().unwrap() = quota;
+    }
+
+    /// Register an instance of `extension` for this tenant.
+    ///
+    /// The same extension can be registered for any number of tenants. Each gets its own instance and state, while
+    /// the compiled component is shared.
+    pub async fn register(&self, extension_id: Id, extension: Extension) -> Result<(), Error> {
+        if self.name.is_empty() || self.name.contains(SEPARATOR) {
+            return Err(Error::Custom(format!("Invalid tenant name {:?}", self.name)));
+        }
+        self.registry.register(self.id(&extension_id), extension).await
+    }
+
+    /// Send a command to one of the tenant's extensions and wait for its response
+    pub async fn call(&self, extension_id: &str, command: Command) -> Result<Response, Error> {
+        self.registry.call(&self.id(extension_id), command).await
+    }
+
+    /// Execute a tool of one of the tenant's extensions, qualified as `extension_id.tool_id`
+    pub async fn execute(&self, tool: &str, params: serde_json::Value) -> Result<Response, Error> {
+        self.registry.execute(&self.id(tool), params).await
+    }
+
+    /// Ids of the tenant's extensions, without the tenant
+    pub fn list_extensions(&self) -> Vec<Id> {
+        let prefix = self.id("");
+        self.registry
+            .list_extensions()
+            .into_iter()
+            .filter_map(|id| id.strip_prefix(&prefix).map(str::to_string))
+            .collect()
+    }
+
+    /// Stop and remove one of the tenant's extensions
+    pub async fn unregister(&self, extension_id: &str) -> Result<(), Error> {
+        self.registry.unregister(&self.id(extension_id)).await
+    }
+
+    /// Stop and remove every extension of the tenant, and forget its quota
+    pub async fn teardown(self) {
+        for extension_id in self.list_extensions() {
+            // It may have exited in the meantime, which is just as good
+            let _ = self.unregister(&extension_id).await;
+        }
+        self.registry.forget_tenant(&self.name);
+    }
+}
+
+/// A call counted against a tenant's quota for as long as this lives
+pub(crate) struct Call(Arc<Usage>);
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        self.0.calls.fetch_sub(1, Ordering::SeqCst);
+    }
+}
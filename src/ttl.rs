@@ -0,0 +1,296 @@
+//! A store of values that expire, kept per extension in memory or on disk, with hit/miss counters.
+//!
+//! The [HTTP response cache](crate::http::Cache) and the [tool result cache](crate::cache::Cache) are built on it.
+//! Expired values are removed when they are looked up, and from memory whenever a value is stored.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::data::Id;
+
+/// Where cached values are kept.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Values live in memory for the lifetime of the cache.
+    Memory,
+    /// Values are written to one file per key under the given directory.
+    Disk(PathBuf),
+}
+
+/// Hit/miss counters of an [HTTP cache](crate::http::Cache) or a [tool result cache](crate::cache::Cache).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub stores: u64,
+}
+
+/// Values of type `V` by extension and key
+#[derive(Debug)]
+pub(crate) struct Store<V> {
+    backend: Backend,
+    shared: Arc<Shared<V>>,
+}
+
+// Not derived, which would require `V: Clone`
+impl<V> Clone for Store<V> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared<V> {
+    /// Entries by extension id, then key
+    memory: Mutex<HashMap<Id, HashMap<String, Entry<V>>>>,
+    stats: Mutex<HashMap<Id, Stats>>,
+}
+
+/// A stored value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry<V> {
+    value: V,
+    /// Expiry as milliseconds since the Unix epoch
+    expires: u64,
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> Store<V> {
+    pub(crate) fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            shared: Arc::new(Shared {
+                memory: Mutex::default(),
+                stats: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Counters for a single extension
+    pub(crate) fn stats(&self, extension_id: &str) -> Stats {
+        self.shared
+            .stats
+            .lock()
+            .unwrap()
+            .get(extension_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Counters for every extension that used the store
+    pub(crate) fn all_stats(&self) -> HashMap<Id, Stats> {
+        self.shared.stats.lock().unwrap().clone()
+    }
+
+    /// Drop every stored value
+    pub(crate) fn clear(&self) -> std::io::Result<()> {
+        self.shared.memory.lock().unwrap().clear();
+        match &self.backend {
+            Backend::Memory => Ok(()),
+            Backend::Disk(dir) if dir.exists() => std::fs::remove_dir_all(dir),
+            Backend::Disk(_) => Ok(()),
+        }
+    }
+
+    /// Drop every stored value of an extension
+    pub(crate) fn purge(&self, extension_id: &str) -> std::io::Result<()> {
+        self.shared.memory.lock().unwrap().remove(extension_id);
+        match &self.backend {
+            Backend::Memory => Ok(()),
+            Backend::Disk(dir) => match std::fs::remove_dir_all(dir.join(dir_name(extension_id))) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn record(&self, extension_id: &str, f: impl FnOnce(&mut Stats)) {
+        let mut stats = self.shared.stats.lock().unwrap();
+        f(stats.entry(extension_id.to_string()).or_default());
+    }
+
+    fn path(dir: &Path, extension_id: &str, key: &str) -> PathBuf {
+        dir.join(dir_name(extension_id)).join(format!("{key}.json"))
+    }
+
+    /// A stored value that has not expired, counting the lookup as a hit or a miss. Expired values are removed.
+    pub(crate) async fn get(&self, extension_id: &str, key: &str) -> Option<V> {
+        let entry = match &self.backend {
+            Backend::Memory => self
+                .shared
+                .memory
+                .lock()
+                .unwrap()
+                .get_mut(extension_id)
+                .and_then(|entries| {
+                    if entries.get(key).is_some_and(|entry| entry.expires <= now()) {
+                        entries.remove(key);
+                    }
+                    entries.get(key).cloned()
+                }),
+            Backend::Disk(dir) => {
+                let path = Self::path(dir, extension_id, key);
+                match tokio::fs::read(&path).await {
+                    Ok(bytes) => {
+                        // Expired or unreadable either way
+                        let entry = serde_json::from_slice::<Entry<V>>(&bytes)
+                            .ok()
+                            .filter(|entry| entry.expires > now());
+                        if entry.is_none() {
+                            let _ = tokio::fs::remove_file(&path).await;
+                        }
+                        entry
+                    }
+                    Err(_) => None,
+                }
+            }
+        };
+
+        match entry {
+            Some(entry) => {
+                self.record(extension_id, |s| s.hits += 1);
+                Some(entry.value)
+            }
+            None => {
+                self.record(extension_id, |s| s.misses += 1);
+                None
+            }
+        }
+    }
+
+    /// Store a value for `ttl`
+    pub(crate) async fn put(&self, extension_id: &str, key: String, value: V, ttl: Duration) {
+        let now = now();
+        let entry = Entry {
+            value,
+            expires: now.saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX)),
+        };
+
+        match &self.backend {
+            Backend::Memory => {
+                // Values that are never looked up again would otherwise stay forever
+                let mut memory = self.shared.memory.lock().unwrap();
+                memory.retain(|_, entries| {
+                    entries.retain(|_, entry| entry.expires > now);
+                    !entries.is_empty()
+                });
+                memory.entry(extension_id.to_string()).or_default().insert(key, entry);
+            }
+            Backend::Disk(dir) => {
+                let path = Self::path(dir, extension_id, &key);
+                let write = async {
+                    if let Some(parent) = path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    let json = serde_json::to_vec(&entry).map_err(std::io::Error::other)?;
+                    tokio::fs::write(&path, json).await
+                };
+                if let Err(e) = write.await {
+                    eprintln!("Failed to write cache entry {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        self.record(extension_id, |s| s.stores += 1);
+    }
+}
+
+/// A directory name for an extension's entries that stays inside the cache directory whatever the id, e.g. for
+/// tenant ids like `alice/polygon`
+fn dir_name(extension_id: &str) -> String {
+    extension_id.bytes().fold(String::new(), |mut name, byte| {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            byte => name.push_str(&format!("%{:02X}", byte)),
+        }
+        name
+    })
+}
+
+/// Milliseconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_expired_values_are_removed() {
+        let dir = std::env::temp_dir().join(format!("emporium-ttl-{}", std::process::id()));
+        for store in [Store::new(Backend::Memory), Store::new(Backend::Disk(dir.clone()))] {
+            store.put("kv", "fresh".to_string(), 1, Duration::from_secs(60)).await;
+            store.put("kv", "stale".to_string(), 2, Duration::ZERO).await;
+            store
+                .put("kv", "brief".to_string(), 3, Duration::from_millis(500))
+                .await;
+
+            assert_eq!(store.get("kv", "fresh").await, Some(1));
+            assert_eq!(store.get("kv", "stale").await, None);
+            assert_eq!(store.get("kv", "brief").await, Some(3));
+            assert_eq!(
+                store.stats("kv"),
+                Stats {
+                    hits: 2,
+                    misses: 1,
+                    stores: 3
+                }
+            );
+
+            // Removed, not just skipped
+            let stored = match &store.backend {
+                Backend::Memory => store.shared.memory.lock().unwrap()["kv"].len(),
+                Backend::Disk(dir) => std::fs::read_dir(dir.join("kv")).unwrap().count(),
+            };
+            assert_eq!(stored, 2);
+
+            store.purge("kv").unwrap();
+            assert_eq!(store.get("kv", "fresh").await, None);
+            store.clear().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_prunes_on_insert() {
+        let store = Store::new(Backend::Memory);
+        for i in 0..100 {
+            let id = if i % 2 == 0 { "kv" } else { "polygon" };
+            store
+                .put(id, format!("https://example.com/{i}"), i, Duration::ZERO)
+                .await;
+        }
+        store.put("kv", "fresh".to_string(), 100, Duration::from_secs(60)).await;
+
+        // None of the expired values was looked up again, and all are gone
+        let memory = store.shared.memory.lock().unwrap();
+        assert_eq!(memory.len(), 1);
+        assert_eq!(memory["kv"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disk_backend_stays_in_dir() {
+        let dir = std::env::temp_dir().join(format!("emporium-ttl-dir-{}", std::process::id()));
+        let store = Store::new(Backend::Disk(dir.clone()));
+
+        for id in ["alice/polygon", "../escape"] {
+            store.put(id, "stale".to_string(), (), Duration::ZERO).await;
+            let path = Store::<()>::path(&dir, id, "stale");
+            assert_eq!(path.parent().unwrap().parent(), Some(dir.as_path()));
+            assert!(path.exists());
+
+            assert!(store.get(id, "stale").await.is_none());
+            assert!(!path.exists());
+        }
+        store.clear().unwrap();
+    }
+}
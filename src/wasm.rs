@@ -1,31 +1,73 @@
 //! WASM extension support
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use futures::StreamExt;
 use futures::channel::mpsc;
+use futures::stream::FuturesUnordered;
 use sipper::{Sipper, sipper};
 use wasmtime::component::{Component, Linker};
 use wasmtime::{Engine, Store};
 
 use crate::Error;
+use crate::audit::{self, Audit};
+use crate::bus;
 use crate::data::{Command, Id, Response};
+use crate::extension::Manifest;
+use crate::filesystem;
+use crate::http;
+use crate::inspect;
+use crate::metrics::{self, Recorder};
+use crate::permission::{Capability, Permissions};
+use crate::redact::{self, Redactor};
+use crate::registry::Peers;
+use crate::signature::Trust;
+use crate::state::Saved;
 
 /// Public type aliases for easier consumer access
 pub type Sender = futures::channel::mpsc::UnboundedSender<Command>;
 pub type Receiver = futures::channel::mpsc::UnboundedReceiver<Command>;
 
 pub(crate) struct State {
+    id: Id,
+    version: String,
     table: wasmtime_wasi::ResourceTable,
     wasi: wasmtime_wasi::WasiCtx,
     http: wasmtime_wasi_http::types::WasiHttpCtx,
+    http_cache: Option<http::Cache>,
+    permissions: Option<Permissions>,
+    secrets: HashMap<String, String>,
+    audit: Option<Audit>,
+    redactor: Redactor,
+    manifest: Option<Manifest>,
+    peers: Option<Peers>,
+    metrics: Option<Recorder>,
+    memory: metrics::Memory,
 }
 
 // TODO: Arc not Clone?
 #[derive(Clone)]
 pub struct Extension {
     id: Id,
+    path: PathBuf,
     wasm_bytes: Vec<u8>,
+    /// Compiled once and shared by every instance started from this extension or its clones
+    component: Arc<OnceLock<Component>>,
     config: String,
+    http_cache: Option<http::Cache>,
+    permissions: Option<Permissions>,
+    secrets: HashMap<String, String>,
+    dirs: Vec<(PathBuf, String)>,
+    audit: Option<Audit>,
+    manifest: Option<Manifest>,
+    sensitive_fields: Vec<String>,
+    peers: Option<Peers>,
+    metrics: Option<Recorder>,
+    fuel: bool,
+    instances: usize,
 }
 
 pub(crate) mod bindings {
@@ -37,6 +79,8 @@ pub(crate) mod bindings {
     });
 }
 
+use bindings::exports::emporium::extensions::extension::{GuestInstance, Metadata};
+
 // Implement the types::Host trait (empty trait required by add_to_linker)
 impl bindings::emporium::extensions::types::Host for State {}
 
@@ -60,6 +104,32 @@ impl wasmtime_wasi_http::types::WasiHttpView for State {
     fn ctx(&mut self) -> &mut wasmtime_wasi_http::types::WasiHttpCtx {
         &mut self.http
     }
+
+    fn send_request(
+        &mut self,
+        request: ::http::Request<wasmtime_wasi_http::body::HyperOutgoingBody>,
+        config: wasmtime_wasi_http::types::OutgoingRequestConfig,
+    ) -> wasmtime_wasi_http::HttpResult<wasmtime_wasi_http::types::HostFutureIncomingResponse> {
+        let outgoing = http::Outgoing {
+            extension_id: &self.id,
+            version: &self.version,
+            cache: self.http_cache.as_ref(),
+            permissions: self.permissions.as_ref(),
+            audit: self.audit.as_ref(),
+            metrics: self.metrics.as_ref(),
+            redactor: &self.redactor,
+        };
+
+        Ok(http::send(outgoing, request, config))
+    }
+}
+
+impl State {
+    pub(crate) fn audit(&self, event: audit::Event) {
+        if let Some(audit) = &self.audit {
+            audit.record(&self.id, self.redactor.event(event));
+        }
+    }
 }
 
 // Implement the log function that extensions can call
@@ -74,7 +144,123 @@ impl bindings::ExtensionWorldImports for State {
         Self: 'b,
     {
         Box::pin(async move {
+            let message = self.redactor.redact(&message);
             eprintln!("[{}] {}", level, message);
+            self.audit(audit::Event::Log { level, message });
+        })
+    }
+
+    fn secret<'a, 'b>(&'a mut self, name: String) -> Pin<Box<dyn futures::Future<Output = Option<String>> + Send + 'b>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let value = self.secrets.get(&name).cloned();
+
+            let granted = match (&value, &self.permissions) {
+                (Some(_), Some(permissions)) => {
+                    let capability = Capability::Secret { name: name.clone() };
+                    permissions.check(&self.id, &self.version, capability).await
+                }
+                (value, _) => value.is_some(),
+            };
+            self.audit(audit::Event::Secret { name, granted });
+
+            value.filter(|_| granted)
+        })
+    }
+
+    fn call_tool<'a, 'b>(
+        &'a mut self,
+        extension: String,
+        tool: String,
+        params: String,
+    ) -> Pin<Box<dyn futures::Future<Output = Result<String, String>> + Send + 'b>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let declared = self
+                .manifest
+                .as_ref()
+                .is_some_and(|manifest| manifest.may_call(&extension, &tool));
+
+            let granted = match (declared, &self.permissions) {
+                (true, Some(permissions)) => {
+                    let capability = Capability::Tool {
+                        extension_id: extension.clone(),
+                        tool_id: tool.clone(),
+                    };
+                    permissions.check(&self.id, &self.version, capability).await
+                }
+                (declared, _) => declared,
+            };
+
+            let result = if !declared {
+                Err(format!(
+                    "{} does not declare calls to {}.{} in its manifest",
+                    self.id, extension, tool
+                ))
+            } else if !granted {
+                Err(format!("{} is not allowed to call {}.{}", self.id, extension, tool))
+            } else {
+                match (&self.peers, serde_json::from_str(&params)) {
+                    (Some(peers), Ok(params)) => peers.execute(&self.id, &extension, &tool, params).await,
+                    (None, _) => Err(format!("{} is not in a registry", self.id)),
+                    (_, Err(e)) => Err(format!("Invalid params: {}", e)),
+                }
+            };
+
+            self.audit(audit::Event::Tool {
+                target: extension,
+                tool_id: tool,
+                granted,
+                error: result.as_ref().err().cloned(),
+            });
+            result
+        })
+    }
+
+    fn publish<'a, 'b>(
+        &'a mut self,
+        topic: String,
+        payload: String,
+    ) -> Pin<Box<dyn futures::Future<Output = Result<(), String>> + Send + 'b>>
+    where
+        'a: 'b,
+        Self: 'b,
+    {
+        Box::pin(async move {
+            let granted = self
+                .manifest
+                .as_ref()
+                .is_some_and(|manifest| manifest.capabilities.iter().any(|c| c == bus::CAPABILITY));
+            self.audit(audit::Event::Publish {
+                topic: topic.clone(),
+                granted,
+            });
+
+            if !granted {
+                return Err(format!(
+                    "{} needs the `{}` capability in its manifest to publish",
+                    self.id,
+                    bus::CAPABILITY
+                ));
+            }
+            let payload = serde_json::from_str(&payload).map_err(|e| format!("Invalid payload: {}", e))?;
+            let peers = self
+                .peers
+                .as_ref()
+                .ok_or_else(|| format!("{} is not in a registry", self.id))?;
+
+            peers.publish(bus::Message {
+                topic,
+                source: Some(self.id.clone()),
+                payload,
+            });
+            Ok(())
         })
     }
 }
@@ -86,49 +272,315 @@ impl Extension {
         self
     }
 
+    /// Serve the extension's outgoing `GET` requests through an HTTP cache
+    pub fn with_http_cache(mut self, cache: http::Cache) -> Self {
+        self.http_cache = Some(cache);
+        self
+    }
+
+    /// Ask `permissions` before the extension reaches the network, the filesystem or a secret
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+
+    /// Make a secret available to the extension through the `secret` import
+    pub fn with_secret(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.secrets.insert(name.into(), value.into());
+        self
+    }
+
+    /// Record everything the extension does in `audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Give the extension access to the host directory `host` at `guest` in its filesystem
+    pub fn with_dir(mut self, host: impl Into<PathBuf>, guest: impl Into<String>) -> Self {
+        self.dirs.push((host.into(), guest.into()));
+        self
+    }
+
+    /// Treat the config field `name` as sensitive, in addition to those the manifest schema marks as secret
+    pub fn with_sensitive_field(mut self, name: impl Into<String>) -> Self {
+        self.sensitive_fields.push(name.into());
+        self
+    }
+
+    /// Check the manifest's grants against the extension, e.g. that it declares `network` before importing HTTP
+    pub fn with_manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Meter the fuel the extension burns handling each command, reported in its [metrics](crate::metrics).
+    ///
+    /// Metering is off by default, since it makes guest code run slower.
+    pub fn with_fuel_metering(mut self) -> Self {
+        self.fuel = true;
+        self
+    }
+
+    /// Run `instances` instances of the component, so that as many commands can be handled at once. Each instance
+    /// runs on its own task, so commands that keep the CPU busy run in parallel on a multi-threaded runtime.
+    ///
+    /// Each instance has its own memory and is created with the same config, so this only suits extensions that
+    /// keep no state between commands.
+    pub fn with_instances(mut self, instances: usize) -> Self {
+        self.instances = instances.max(1);
+        self
+    }
+
+    /// Let the extension call tools of the other extensions in a registry
+    pub(crate) fn with_peers(mut self, peers: Peers) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Record what the extension does in `recorder`
+    pub(crate) fn with_metrics(mut self, recorder: Recorder) -> Self {
+        self.metrics = Some(recorder);
+        self
+    }
+
+    /// The manifest the extension was loaded with, if any
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Topic patterns the extension subscribes to, if its manifest enables the `events` capability
+    pub fn subscriptions(&self) -> Vec<String> {
+        match &self.manifest {
+            Some(manifest) if manifest.capabilities.iter().any(|c| c == bus::CAPABILITY) => {
+                manifest.subscriptions.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Capabilities enabled in the extension's manifest, or none without a manifest
+    pub fn capabilities(&self) -> Vec<String> {
+        self.manifest
+            .as_ref()
+            .map(|manifest| manifest.capabilities.clone())
+            .unwrap_or_default()
+    }
+
+    /// List the component's imports and exports and check them against what the runtime provides
+    pub fn inspect(&self) -> Result<inspect::Report, Error> {
+        let engine = engine(self.fuel)?;
+        let component = self.component(&engine)?;
+        let linker = linker(&engine)?;
+
+        Ok(inspect::inspect(
+            &self.id,
+            &engine,
+            &component,
+            &linker,
+            self.manifest.as_ref(),
+        ))
+    }
+
+    /// The component file the extension was loaded from
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// How to load the extension again, leaving out its secrets and sensitive config fields
+    pub(crate) fn saved(&self) -> Saved {
+        let mut config = serde_json::from_str(&self.config).unwrap_or_default();
+        if let serde_json::Value::Object(fields) = &mut config {
+            for field in self.sensitive() {
+                fields.remove(&field);
+            }
+        }
+
+        let mut sensitive = self.sensitive_fields.clone();
+        sensitive.sort();
+        sensitive.dedup();
+
+        Saved {
+            id: self.id.clone(),
+            version: self.manifest.as_ref().map(|manifest| manifest.version.clone()),
+            path: self.path.clone(),
+            config,
+            sensitive,
+            dirs: self.dirs.clone(),
+        }
+    }
+
+    /// Config fields the manifest schema marks as secret, and those added with `with_sensitive_field`
+    fn sensitive(&self) -> Vec<String> {
+        let mut fields = self.sensitive_fields.clone();
+        if let Some(manifest) = &self.manifest {
+            fields.extend(redact::sensitive_fields(&manifest.schema));
+        }
+        fields
+    }
+
+    /// The component compiled for `engine`
+    fn component(&self, engine: &Engine) -> Result<Component, Error> {
+        if let Some(component) = self.component.get()
+            && Engine::same(component.engine(), engine)
+        {
+            return Ok(component.clone());
+        }
+
+        // Only the first compilation is kept, since extensions rarely switch engines
+        let component = Component::from_binary(engine, &self.wasm_bytes)?;
+        let _ = self.component.set(component.clone());
+        Ok(component)
+    }
+
+    /// A redactor for the extension's secrets and sensitive config values
+    fn redactor(&self) -> Redactor {
+        let config = serde_json::from_str(&self.config).unwrap_or_default();
+
+        Redactor::new()
+            .with_config(&config, &self.sensitive())
+            .with_values(self.secrets.values().cloned())
+    }
+
+    /// Compile, validate and instantiate the component, then fetch its metadata.
+    ///
+    /// Also returns the directories the extension was allowed to open, for any further instances.
+    async fn start(&self) -> Result<(Store<State>, bindings::ExtensionWorld, Metadata, Vec<(PathBuf, String)>), Error> {
+        let engine = engine(self.fuel)?;
+        let component = self.component(&engine)?;
+        let linker = linker(&engine)?;
+
+        inspect::inspect(&self.id, &engine, &component, &linker, self.manifest.as_ref()).into_result()?;
+
+        // Permissions are asked for a version, and the component is instantiated with what it was granted, so that
+        // anything it runs on instantiation already has its final context. Without a manifest, the version comes
+        // from an instance that is given no directories and then dropped.
+        let version = match &self.manifest {
+            Some(manifest) => manifest.version.clone(),
+            None if self.dirs.is_empty() => String::new(),
+            None => {
+                let (mut store, bindings) = self.instantiate("", &[]).await?;
+                let metadata = bindings
+                    .emporium_extensions_extension()
+                    .call_get_metadata(&mut store)
+                    .await?;
+                metadata.version
+            }
+        };
+        let dirs = self.granted_dirs(&version).await;
+
+        let (mut store, bindings) = self.instantiate(&version, &dirs).await?;
+
+        // Get metadata
+        let metadata = bindings
+            .emporium_extensions_extension()
+            .call_get_metadata(&mut store)
+            .await?;
+
+        store.data_mut().version = metadata.version.clone();
+
+        Ok((store, bindings, metadata, dirs))
+    }
+
+    /// The directories to preopen that the extension is allowed to open at `version`, auditing each decision
+    async fn granted_dirs(&self, version: &str) -> Vec<(PathBuf, String)> {
+        let redactor = self.redactor();
+        let mut dirs = Vec::new();
+        for (host, guest) in &self.dirs {
+            let capability = Capability::Filesystem { path: host.clone() };
+            let allowed = match &self.permissions {
+                Some(permissions) => permissions.check(&self.id, version, capability).await,
+                None => true,
+            };
+            if let Some(audit) = &self.audit {
+                let event = audit::Event::Filesystem {
+                    path: host.clone(),
+                    granted: allowed,
+                };
+                audit.record(&self.id, redactor.event(event));
+            }
+            if allowed {
+                dirs.push((host.clone(), guest.clone()));
+            }
+        }
+        dirs
+    }
+
+    /// Instantiate the component in a new store for `version`, with `dirs` preopened
+    async fn instantiate(
+        &self,
+        version: &str,
+        dirs: &[(PathBuf, String)],
+    ) -> Result<(Store<State>, bindings::ExtensionWorld), Error> {
+        let engine = engine(self.fuel)?;
+        let component = self.component(&engine)?;
+        let linker = linker(&engine)?;
+        let redactor = self.redactor();
+
+        let mut store = Store::new(
+            &engine,
+            State {
+                id: self.id.clone(),
+                version: version.to_string(),
+                table: wasmtime_wasi::ResourceTable::new(),
+                wasi: wasi_ctx(dirs, &redactor),
+                http: wasmtime_wasi_http::types::WasiHttpCtx::new(),
+                http_cache: self.http_cache.clone(),
+                permissions: self.permissions.clone(),
+                secrets: self.secrets.clone(),
+                audit: self.audit.clone(),
+                redactor,
+                manifest: self.manifest.clone(),
+                peers: self.peers.clone(),
+                metrics: self.metrics.clone(),
+                memory: metrics::Memory::new(self.metrics.clone()),
+            },
+        );
+        store.limiter(|state| &mut state.memory);
+        store.epoch_deadline_async_yield_and_update(1);
+        if self.fuel {
+            // Fuel is only metered, never a limit
+            store.set_fuel(u64::MAX)?;
+        }
+
+        let bindings = bindings::ExtensionWorld::instantiate_async(&mut store, &component, &linker).await?;
+        Ok((store, bindings))
+    }
+
+    /// Create the extension's instance resource in a started store
+    async fn worker(&self, mut store: Store<State>, bindings: bindings::ExtensionWorld) -> Result<Worker, String> {
+        let instance = bindings.emporium_extensions_extension().instance();
+        match instance.call_new(&mut store, &self.config).await {
+            Ok(resource) => Ok(Worker {
+                store,
+                bindings,
+                resource,
+            }),
+            Err(e) => Err(self.redactor().redact(&format!("Failed to create instance: {}", e))),
+        }
+    }
+
     /// Convert the extension into a sipper that emits responses.
     /// The sipper will first emit a Connected response with a message sender.
+    /// If the extension cannot be started, it emits a single Error response instead.
+    ///
+    /// With [several instances](Self::with_instances), commands are handled by whichever instance is idle, and
+    /// their responses are still emitted in the order the commands were sent.
     pub fn into_sipper(self) -> impl Sipper<(), Response> {
         let (msg_tx, mut msg_rx): (Sender, Receiver) = mpsc::unbounded();
 
         sipper(move |mut output| async move {
-            let mut config = wasmtime::Config::new();
-            config.async_support(true);
-            let engine = Engine::new(&config).unwrap();
-            let component = Component::from_binary(&engine, &self.wasm_bytes).unwrap();
-
-            // Set up the WASM instance
-            let mut linker = Linker::new(&engine);
-            bindings::ExtensionWorld::add_to_linker(&mut linker, |state: &mut State| state).unwrap();
-
-            // Add WASI support
-            wasmtime_wasi::add_to_linker_async(&mut linker).unwrap();
-
-            // Add WASI HTTP support
-            wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
-
-            // Create WASI context
-            let wasi = wasmtime_wasi::WasiCtxBuilder::new().inherit_stdio().build();
-
-            let mut store = Store::new(
-                &engine,
-                State {
-                    table: wasmtime_wasi::ResourceTable::new(),
-                    wasi,
-                    http: wasmtime_wasi_http::types::WasiHttpCtx::new(),
-                },
-            );
-
-            let bindings = bindings::ExtensionWorld::instantiate_async(&mut store, &component, &linker)
-                .await
-                .unwrap();
-
-            // Get metadata
-            let metadata = bindings
-                .emporium_extensions_extension()
-                .call_get_metadata(&mut store)
-                .await
-                .unwrap();
+            let (store, bindings, metadata, dirs) = match self.start().await {
+                Ok(started) => started,
+                Err(e) => {
+                    let error = self.redactor().redact(&e.to_string());
+                    eprintln!("Extension {} failed to start: {}", self.id, error);
+                    output.send(Response::Error(error)).await;
+                    return;
+                }
+            };
+            let redactor = store.data().redactor.clone();
+            let version = metadata.version.clone();
 
             output
                 .send(Response::Metadata {
@@ -139,52 +591,72 @@ impl Extension {
                 })
                 .await;
 
-            // Create an extension instance
-            let instance = bindings.emporium_extensions_extension().instance();
-
-            // Create instance resource with config
-            let instance_resource = instance.call_new(&mut store, &self.config).await.unwrap();
+            // Create the instances, each in its own store, and their instance resources with config
+            let mut idle = Vec::with_capacity(self.instances);
+            let mut started = Some((store, bindings));
+            for _ in 0..self.instances {
+                let (store, bindings) = match started.take() {
+                    Some(started) => started,
+                    None => match self.instantiate(&version, &dirs).await {
+                        Ok(started) => started,
+                        Err(e) => {
+                            output.send(Response::Error(redactor.redact(&e.to_string()))).await;
+                            return;
+                        }
+                    },
+                };
+                match self.worker(store, bindings).await {
+                    Ok(worker) => idle.push(worker),
+                    Err(error) => {
+                        output.send(Response::Error(error)).await;
+                        return;
+                    }
+                }
+            }
 
             // Send the Connected response with the message sender
             output.send(Response::Connected(msg_tx.clone())).await;
 
-            // Process messages
-            while let Some(cmd) = msg_rx.next().await {
-                // Serialize the command to JSON
-                let cmd_json = match serde_json::to_string(&cmd) {
-                    Ok(json) => json,
-                    Err(e) => {
-                        output
-                            .send(Response::Error(format!("Failed to serialize command: {}", e)))
-                            .await;
-                        continue;
+            // Process messages, holding back responses until those to earlier commands are out
+            let mut running = FuturesUnordered::new();
+            let mut done = BTreeMap::new();
+            let (mut sent, mut emitted) = (0u64, 0u64);
+            loop {
+                tokio::select! {
+                    cmd = msg_rx.next(), if !idle.is_empty() => {
+                        let Some(cmd) = cmd else {
+                            break;
+                        };
+                        let worker = idle.pop().expect("an idle instance");
+                        running.push(Running(tokio::spawn(handle(
+                            worker,
+                            sent,
+                            cmd,
+                            redactor.clone(),
+                            self.metrics.clone(),
+                        ))));
+                        sent += 1;
                     }
-                };
-
-                eprintln!("Processing command: {}", cmd_json);
-
-                // Pass the JSON string to the extension
-                match instance.call_update(&mut store, instance_resource, &cmd_json).await {
-                    Ok(Ok(response_json)) => {
-                        // Try to deserialize the response as our Response enum
-                        match serde_json::from_str::<Response>(&response_json) {
-                            Ok(response) => {
-                                output.send(response).await;
-                            }
-                            Err(_) => {
-                                // Fallback for backwards compatibility - treat as raw data
-                                output.send(Response::Data(response_json)).await;
+                    Some((seq, worker, result)) = running.next() => {
+                        // A trapped instance cannot be entered again, so it is not reused
+                        if result.is_ok() {
+                            idle.push(worker);
+                        }
+                        done.insert(seq, result);
+
+                        while let Some(result) = done.remove(&emitted) {
+                            emitted += 1;
+                            match result {
+                                Ok(response) => output.send(response).await,
+                                Err(error) => {
+                                    // WASM runtime error, so stop
+                                    eprintln!("Extension {} trapped: {}", self.id, error);
+                                    output.send(Response::Error(error)).await;
+                                    return;
+                                }
                             }
                         }
                     }
-                    Ok(Err(error)) => {
-                        // Extension returned an error
-                        output.send(Response::Error(error)).await;
-                    }
-                    Err(e) => {
-                        // WASM runtime error
-                        output.send(Response::Error(format!("Runtime error: {}", e))).await;
-                    }
                 }
             }
 
@@ -193,6 +665,188 @@ impl Extension {
     }
 }
 
+/// One instance of the component, in its own store
+struct Worker {
+    store: Store<State>,
+    bindings: bindings::ExtensionWorld,
+    resource: wasmtime::component::ResourceAny,
+}
+
+/// A command running on its own task, so that instances run in parallel, aborted if dropped before it is done
+struct Running(tokio::task::JoinHandle<(u64, Worker, Result<Response, String>)>);
+
+impl Future for Running {
+    type Output = (u64, Worker, Result<Response, String>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|joined| joined.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Handle the `seq`th command on `worker`, recording it in `metrics`, and give the worker back with the result
+async fn handle(
+    mut worker: Worker,
+    seq: u64,
+    cmd: Command,
+    redactor: Redactor,
+    metrics: Option<Recorder>,
+) -> (u64, Worker, Result<Response, String>) {
+    let started = Instant::now();
+    let fuel = worker.store.get_fuel().unwrap_or_default();
+
+    let instance = worker.bindings.emporium_extensions_extension().instance();
+    let result = update(&mut worker.store, &instance, worker.resource, &cmd, &redactor).await;
+
+    if let Some(metrics) = &metrics {
+        let tool_id = match &cmd {
+            Command::ExecuteTool { tool_id, .. } => Some(tool_id.as_str()),
+            _ => None,
+        };
+        let success = matches!(&result, Ok(response) if !matches!(response, Response::Error(_)));
+        let consumed = fuel.saturating_sub(worker.store.get_fuel().unwrap_or_default());
+        metrics.command(tool_id, success, started.elapsed(), consumed);
+    }
+
+    (seq, worker, result)
+}
+
+/// Handle one command, answering `View` with the instance's view and passing anything else to `update`.
+///
+/// Errors the extension returns are responses; only a trap, after which the instance is unusable, is an `Err`.
+async fn update(
+    store: &mut Store<State>,
+    instance: &GuestInstance<'_>,
+    resource: wasmtime::component::ResourceAny,
+    cmd: &Command,
+    redactor: &Redactor,
+) -> Result<Response, String> {
+    if let Command::View = cmd {
+        return match instance.call_view(&mut *store, resource).await {
+            Ok(view) => Ok(Response::Data(redactor.redact(&view))),
+            Err(e) => Err(redactor.redact(&format!("Runtime error: {}", e))),
+        };
+    }
+
+    // Serialize the command to JSON
+    let cmd_json = match serde_json::to_string(cmd) {
+        Ok(json) => json,
+        Err(e) => return Ok(Response::Error(format!("Failed to serialize command: {}", e))),
+    };
+
+    eprintln!("Processing command: {}", redactor.redact(&cmd_json));
+    store.data().audit(audit::Event::Command {
+        command: cmd_json.clone(),
+    });
+
+    // Pass the JSON string to the extension
+    match instance.call_update(&mut *store, resource, &cmd_json).await {
+        Ok(Ok(response_json)) => {
+            // Try to deserialize the response as our Response enum
+            match serde_json::from_str::<Response>(&response_json) {
+                Ok(Response::Error(error)) => Ok(Response::Error(redactor.redact(&error))),
+                Ok(response) => Ok(response),
+                // Fallback for backwards compatibility - treat as raw data
+                Err(_) => Ok(Response::Data(response_json)),
+            }
+        }
+        // Extension returned an error
+        Ok(Err(error)) => Ok(Response::Error(redactor.redact(&error))),
+        Err(e) => Err(redactor.redact(&format!("Runtime error: {}", e))),
+    }
+}
+
+/// How often a guest running wasm without calling the host yields to the executor, so that it can be stopped
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// The engine every extension runs on, so that compiled components can be shared. Extensions whose fuel is metered
+/// share another one, since only code compiled for metering counts fuel.
+///
+/// Each engine has a thread that advances its epoch every [`EPOCH_TICK`], and every store yields when the epoch
+/// changes. A guest spinning in a loop thus never holds an executor thread for long, and dropping it stops it.
+fn engine(fuel: bool) -> Result<Engine, Error> {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    static METERED: OnceLock<Engine> = OnceLock::new();
+    let shared = if fuel { &METERED } else { &ENGINE };
+    if let Some(engine) = shared.get() {
+        return Ok(engine.clone());
+    }
+
+    let mut config = wasmtime::Config::new();
+    config.async_support(true);
+    config.consume_fuel(fuel);
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    Ok(shared
+        .get_or_init(|| {
+            let ticked = engine.weak();
+            std::thread::spawn(move || {
+                while let Some(engine) = ticked.upgrade() {
+                    engine.increment_epoch();
+                    drop(engine);
+                    std::thread::sleep(EPOCH_TICK);
+                }
+            });
+            engine
+        })
+        .clone())
+}
+
+/// A linker with everything the runtime provides to extensions
+fn linker(engine: &Engine) -> Result<Linker<State>, Error> {
+    let mut linker = Linker::new(engine);
+    bindings::ExtensionWorld::add_to_linker(&mut linker, |state: &mut State| state)?;
+
+    // Add WASI support, auditing what extensions open
+    wasmtime_wasi::add_to_linker_async(&mut linker)?;
+    filesystem::add_to_linker(&mut linker)?;
+
+    // Add WASI HTTP support
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+
+    Ok(linker)
+}
+
+/// Build a WASI context that forwards stdio, redacted when there are secrets, and preopens `dirs`
+fn wasi_ctx(dirs: &[(PathBuf, String)], redactor: &Redactor) -> wasmtime_wasi::WasiCtx {
+    let mut builder = wasmtime_wasi::WasiCtxBuilder::new();
+    if redactor.is_empty() {
+        builder.inherit_stdio();
+    } else {
+        builder
+            .inherit_stdin()
+            .stdout(redact::RedactedStdio {
+                stdio: redact::Stdio::Stdout,
+                redactor: redactor.clone(),
+            })
+            .stderr(redact::RedactedStdio {
+                stdio: redact::Stdio::Stderr,
+                redactor: redactor.clone(),
+            });
+    }
+
+    for (host, guest) in dirs {
+        let preopened = builder.preopened_dir(
+            host,
+            guest.as_str(),
+            wasmtime_wasi::DirPerms::all(),
+            wasmtime_wasi::FilePerms::all(),
+        );
+        if let Err(e) = preopened {
+            eprintln!("Failed to preopen {}: {}", host.display(), e);
+        }
+    }
+
+    builder.build()
+}
+
 /// Load an extension by ID
 pub async fn load(id: Id, config: String, path: std::path::PathBuf) -> Result<Extension, Error> {
     let wasm_path = if path.extension().and_then(|s| s.to_str()) == Some("wasm") {
@@ -204,14 +858,46 @@ pub async fn load(id: Id, config: String, path: std::path::PathBuf) -> Result<Ex
     };
 
     if wasm_path.exists() {
-        let wasm_bytes = std::fs::read(wasm_path)?;
-
-        Ok(Extension { id, wasm_bytes, config })
+        let wasm_bytes = std::fs::read(&wasm_path)?;
+
+        Ok(Extension {
+            id,
+            path: wasm_path,
+            wasm_bytes,
+            component: Arc::default(),
+            config,
+            http_cache: None,
+            permissions: None,
+            secrets: HashMap::new(),
+            dirs: Vec::new(),
+            audit: None,
+            manifest: None,
+            sensitive_fields: Vec::new(),
+            peers: None,
+            metrics: None,
+            fuel: false,
+            instances: 1,
+        })
     } else {
         Err(Error::ExtensionNotFound(wasm_path.display().to_string()))
     }
 }
 
+/// Load an extension found by [`list`](crate::list), refusing it unless `trust` accepts its signature.
+///
+/// The component is verified before it is ever compiled.
+pub async fn load_verified(
+    manifest: &Manifest,
+    config: String,
+    path: std::path::PathBuf,
+    trust: &Trust,
+) -> Result<Extension, Error> {
+    let extension = load(manifest.id.clone(), config, path).await?;
+    trust.verify(manifest, &extension.wasm_bytes)?;
+
+    Ok(extension.with_manifest(manifest.clone()))
+}
+
 impl std::fmt::Debug for Extension {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("WasmExtension").field("id", &self.id).finish()
@@ -0,0 +1,190 @@
+;; A test extension that touches files, written by hand in the component text format.
+;;
+;; When it is instantiated, it creates `start.txt` in the first directory it was given, if any. It lists no tools.
+;; Every other command makes it create `note.txt` for writing in that directory, then open `missing.txt` for
+;; reading, which fails unless the file exists.
+(component
+  (import "wasi:filesystem/types@0.2.0" (instance $types
+    (export "descriptor" (type $descriptor (sub resource)))
+    (type $path_flags (flags "symlink-follow"))
+    (export "path-flags" (type $path_flags_type (eq $path_flags)))
+    (type $open_flags (flags "create" "directory" "exclusive" "truncate"))
+    (export "open-flags" (type $open_flags_type (eq $open_flags)))
+    (type $descriptor_flags (flags
+      "read" "write" "file-integrity-sync" "data-integrity-sync" "requested-write-sync" "mutate-directory"))
+    (export "descriptor-flags" (type $descriptor_flags_type (eq $descriptor_flags)))
+    (type $error_code (enum
+      "access" "would-block" "already" "bad-descriptor" "busy" "deadlock" "quota" "exist" "file-too-large"
+      "illegal-byte-sequence" "in-progress" "interrupted" "invalid" "io" "is-directory" "loop" "too-many-links"
+      "message-size" "name-too-long" "no-device" "no-entry" "no-lock" "insufficient-memory" "insufficient-space"
+      "not-directory" "not-empty" "not-recoverable" "unsupported" "no-tty" "no-such-device" "overflow"
+      "not-permitted" "pipe" "read-only" "invalid-seek" "text-file-busy" "cross-device"))
+    (export "error-code" (type $error_code_type (eq $error_code)))
+    (export "[method]descriptor.open-at" (func
+      (param "self" (borrow $descriptor))
+      (param "path-flags" $path_flags_type)
+      (param "path" string)
+      (param "open-flags" $open_flags_type)
+      (param "flags" $descriptor_flags_type)
+      (result (result (own $descriptor) (error $error_code_type)))))))
+  (alias export $types "descriptor" (type $descriptor))
+  (import "wasi:filesystem/preopens@0.2.0" (instance $preopens
+    (alias outer 1 $descriptor (type $outer_descriptor))
+    (export "descriptor" (type $descriptor_type (eq $outer_descriptor)))
+    (export "get-directories" (func (result (list (tuple (own $descriptor_type) string)))))))
+  (alias export $types "[method]descriptor.open-at" (func $open_at))
+  (alias export $preopens "get-directories" (func $get_directories))
+
+  ;; Memory and a bump allocator that never frees, shared by the main module and the canonical ABI
+  (core module $Memory
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 4096))
+    (func (export "realloc") (param $old i32) (param $old_size i32) (param $align i32) (param $size i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get $align) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get $align))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get $size)))
+      (block $done
+        (loop $grow
+          (br_if $done (i32.le_u (global.get $heap) (i32.shl (memory.size) (i32.const 16))))
+          (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1)) (then unreachable))
+          (br $grow)))
+      (memory.copy (local.get $ptr) (local.get $old) (local.get $old_size))
+      (local.get $ptr)))
+  (core instance $memory (instantiate $Memory))
+  (alias core export $memory "memory" (core memory $mem))
+  (alias core export $memory "realloc" (core func $realloc))
+
+  (type $instance (resource (rep i32)))
+  (core func $get_directories_lowered (canon lower (func $get_directories) (memory $mem) (realloc $realloc)))
+  (core func $open_at_lowered (canon lower (func $open_at) (memory $mem)))
+  (core func $instance_new (canon resource.new $instance))
+
+  (core module $Main
+    (import "env" "memory" (memory 1))
+    (import "env" "get-directories" (func $get_directories (param i32)))
+    (import "env" "open-at" (func $open_at (param i32 i32 i32 i32 i32 i32 i32)))
+    (import "env" "instance-new" (func $instance_new (param i32) (result i32)))
+
+    ;; Return areas: 16 for results, 32 for the view, 48 for opens, 64 for the metadata record, 96 for directories
+    (data (i32.const 256) "files")
+    (data (i32.const 264) "Files")
+    (data (i32.const 272) "0.1.0")
+    (data (i32.const 280) "Opens files in its directory")
+    (data (i32.const 320) "{\"type\":\"ToolList\",\"payload\":[]}")
+    (data (i32.const 360) "note.txt")
+    (data (i32.const 376) "missing.txt")
+    (data (i32.const 392) "no directories")
+    (data (i32.const 408) "{}")
+    (data (i32.const 424) "start.txt")
+
+    (func $init
+      (call $get_directories (i32.const 96))
+      (if (i32.load (i32.const 100))
+        (then
+          (call $open_at (i32.load (i32.load (i32.const 96))) (i32.const 0) (i32.const 424) (i32.const 9)
+            (i32.const 1) (i32.const 2) (i32.const 48)))))
+    (start $init)
+
+    (func $ok (param $ptr i32) (param $len i32) (result i32)
+      (i32.store (i32.const 16) (i32.const 0))
+      (i32.store (i32.const 20) (local.get $ptr))
+      (i32.store (i32.const 24) (local.get $len))
+      (i32.const 16))
+
+    (func (export "get-metadata") (result i32)
+      (i32.store (i32.const 64) (i32.const 256))
+      (i32.store (i32.const 68) (i32.const 5))
+      (i32.store (i32.const 72) (i32.const 264))
+      (i32.store (i32.const 76) (i32.const 5))
+      (i32.store (i32.const 80) (i32.const 272))
+      (i32.store (i32.const 84) (i32.const 5))
+      (i32.store (i32.const 88) (i32.const 280))
+      (i32.store (i32.const 92) (i32.const 28))
+      (i32.const 64))
+
+    (func (export "new") (param $config i32) (param $len i32) (result i32)
+      (call $instance_new (i32.const 0)))
+
+    (func (export "update") (param $self i32) (param $command i32) (param $len i32) (result i32)
+      (local $dir i32)
+      ;; `{"type":"ListTools"}`
+      (if (i32.eq (i32.load8_u offset=9 (local.get $command)) (i32.const 76))
+        (then (return (call $ok (i32.const 320) (i32.const 32)))))
+
+      (call $get_directories (i32.const 96))
+      (if (i32.eqz (i32.load (i32.const 100)))
+        (then
+          (i32.store (i32.const 16) (i32.const 1))
+          (i32.store (i32.const 20) (i32.const 392))
+          (i32.store (i32.const 24) (i32.const 14))
+          (return (i32.const 16))))
+      ;; The descriptor of the first (descriptor, name) pair
+      (local.set $dir (i32.load (i32.load (i32.const 96))))
+
+      ;; Open flags `create`, descriptor flags `write`
+      (call $open_at (local.get $dir) (i32.const 0) (i32.const 360) (i32.const 8) (i32.const 1) (i32.const 2)
+        (i32.const 48))
+      ;; No open flags, descriptor flags `read`
+      (call $open_at (local.get $dir) (i32.const 0) (i32.const 376) (i32.const 11) (i32.const 0) (i32.const 1)
+        (i32.const 48))
+      (call $ok (i32.const 408) (i32.const 2)))
+
+    (func (export "view") (param $self i32) (result i32)
+      (i32.store (i32.const 32) (i32.const 408))
+      (i32.store (i32.const 36) (i32.const 2))
+      (i32.const 32)))
+
+  (core instance $main (instantiate $Main
+    (with "env" (instance
+      (export "memory" (memory $mem))
+      (export "get-directories" (func $get_directories_lowered))
+      (export "open-at" (func $open_at_lowered))
+      (export "instance-new" (func $instance_new))))))
+
+  (type $metadata (record
+    (field "id" string)
+    (field "name" string)
+    (field "version" string)
+    (field "description" string)))
+  (func $get_metadata (result $metadata)
+    (canon lift (core func $main "get-metadata") (memory $mem)))
+  (func $new (param "config" string) (result (own $instance))
+    (canon lift (core func $main "new") (memory $mem) (realloc $realloc)))
+  (func $update (param "self" (borrow $instance)) (param "command" string) (result (result string (error string)))
+    (canon lift (core func $main "update") (memory $mem) (realloc $realloc)))
+  (func $view (param "self" (borrow $instance)) (result string)
+    (canon lift (core func $main "view") (memory $mem)))
+
+  ;; Exported types must be named, so the interface is assembled by a component that imports and re-exports them
+  (component $Extension
+    (type $record (record
+      (field "id" string)
+      (field "name" string)
+      (field "version" string)
+      (field "description" string)))
+    (import "metadata-type" (type $metadata_type (eq $record)))
+    (import "instance-type" (type $instance_type (sub resource)))
+    (import "get-metadata" (func $get_metadata (result $metadata_type)))
+    (import "new" (func $new (param "config" string) (result (own $instance_type))))
+    (import "update"
+      (func $update (param "self" (borrow $instance_type)) (param "command" string) (result (result string (error string)))))
+    (import "view" (func $view (param "self" (borrow $instance_type)) (result string)))
+    (export $metadata "metadata" (type $metadata_type))
+    (export $instance "instance" (type $instance_type))
+    (export "get-metadata" (func $get_metadata) (func (result $metadata)))
+    (export "[static]instance.new" (func $new) (func (param "config" string) (result (own $instance))))
+    (export "[method]instance.update" (func $update)
+      (func (param "self" (borrow $instance)) (param "command" string) (result (result string (error string)))))
+    (export "[method]instance.view" (func $view) (func (param "self" (borrow $instance)) (result string))))
+
+  (instance $extension (instantiate $Extension
+    (with "metadata-type" (type $metadata))
+    (with "instance-type" (type $instance))
+    (with "get-metadata" (func $get_metadata))
+    (with "new" (func $new))
+    (with "update" (func $update))
+    (with "view" (func $view))))
+  (export "emporium:extensions/extension@0.1.0" (instance $extension)))
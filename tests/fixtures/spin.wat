@@ -0,0 +1,121 @@
+;; A test extension that never answers, written by hand in the component text format.
+;;
+;; It lists no tools. Every other command, and its view, loop forever without calling the host.
+(component
+  ;; Memory and a bump allocator that never frees, shared by the main module and the canonical ABI
+  (core module $Memory
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 4096))
+    (func (export "realloc") (param $old i32) (param $old_size i32) (param $align i32) (param $size i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get $align) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get $align))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get $size)))
+      (block $done
+        (loop $grow
+          (br_if $done (i32.le_u (global.get $heap) (i32.shl (memory.size) (i32.const 16))))
+          (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1)) (then unreachable))
+          (br $grow)))
+      (memory.copy (local.get $ptr) (local.get $old) (local.get $old_size))
+      (local.get $ptr)))
+  (core instance $memory (instantiate $Memory))
+  (alias core export $memory "memory" (core memory $mem))
+  (alias core export $memory "realloc" (core func $realloc))
+
+  (type $instance (resource (rep i32)))
+  (core func $instance_new (canon resource.new $instance))
+
+  (core module $Main
+    (import "env" "memory" (memory 1))
+    (import "env" "instance-new" (func $instance_new (param i32) (result i32)))
+
+    ;; Return areas: 16 for results, 64 for the metadata record
+    (data (i32.const 256) "spin")
+    (data (i32.const 264) "Spin")
+    (data (i32.const 272) "0.1.0")
+    (data (i32.const 280) "Never answers")
+    (data (i32.const 320) "{\"type\":\"ToolList\",\"payload\":[]}")
+
+    (func $spin
+      (loop $forever (br $forever)))
+
+    (func (export "get-metadata") (result i32)
+      (i32.store (i32.const 64) (i32.const 256))
+      (i32.store (i32.const 68) (i32.const 4))
+      (i32.store (i32.const 72) (i32.const 264))
+      (i32.store (i32.const 76) (i32.const 4))
+      (i32.store (i32.const 80) (i32.const 272))
+      (i32.store (i32.const 84) (i32.const 5))
+      (i32.store (i32.const 88) (i32.const 280))
+      (i32.store (i32.const 92) (i32.const 13))
+      (i32.const 64))
+
+    (func (export "new") (param $config i32) (param $len i32) (result i32)
+      (call $instance_new (i32.const 0)))
+
+    (func (export "update") (param $self i32) (param $command i32) (param $len i32) (result i32)
+      ;; `{"type":"ListTools"}`
+      (if (i32.eq (i32.load8_u offset=9 (local.get $command)) (i32.const 76))
+        (then
+          (i32.store (i32.const 16) (i32.const 0))
+          (i32.store (i32.const 20) (i32.const 320))
+          (i32.store (i32.const 24) (i32.const 32))
+          (return (i32.const 16))))
+      (call $spin)
+      unreachable)
+
+    (func (export "view") (param $self i32) (result i32)
+      (call $spin)
+      unreachable))
+
+  (core instance $main (instantiate $Main
+    (with "env" (instance
+      (export "memory" (memory $mem))
+      (export "instance-new" (func $instance_new))))))
+
+  (type $metadata (record
+    (field "id" string)
+    (field "name" string)
+    (field "version" string)
+    (field "description" string)))
+  (func $get_metadata (result $metadata)
+    (canon lift (core func $main "get-metadata") (memory $mem)))
+  (func $new (param "config" string) (result (own $instance))
+    (canon lift (core func $main "new") (memory $mem) (realloc $realloc)))
+  (func $update (param "self" (borrow $instance)) (param "command" string) (result (result string (error string)))
+    (canon lift (core func $main "update") (memory $mem) (realloc $realloc)))
+  (func $view (param "self" (borrow $instance)) (result string)
+    (canon lift (core func $main "view") (memory $mem)))
+
+  ;; Exported types must be named, so the interface is assembled by a component that imports and re-exports them
+  (component $Extension
+    (type $record (record
+      (field "id" string)
+      (field "name" string)
+      (field "version" string)
+      (field "description" string)))
+    (import "metadata-type" (type $metadata_type (eq $record)))
+    (import "instance-type" (type $instance_type (sub resource)))
+    (import "get-metadata" (func $get_metadata (result $metadata_type)))
+    (import "new" (func $new (param "config" string) (result (own $instance_type))))
+    (import "update"
+      (func $update (param "self" (borrow $instance_type)) (param "command" string) (result (result string (error string)))))
+    (import "view" (func $view (param "self" (borrow $instance_type)) (result string)))
+    (export $metadata "metadata" (type $metadata_type))
+    (export $instance "instance" (type $instance_type))
+    (export "get-metadata" (func $get_metadata) (func (result $metadata)))
+    (export "[static]instance.new" (func $new) (func (param "config" string) (result (own $instance))))
+    (export "[method]instance.update" (func $update)
+      (func (param "self" (borrow $instance)) (param "command" string) (result (result string (error string)))))
+    (export "[method]instance.view" (func $view) (func (param "self" (borrow $instance)) (result string))))
+
+  (instance $extension (instantiate $Extension
+    (with "metadata-type" (type $metadata))
+    (with "instance-type" (type $instance))
+    (with "get-metadata" (func $get_metadata))
+    (with "new" (func $new))
+    (with "update" (func $update))
+    (with "view" (func $view))))
+  (export "emporium:extensions/extension@0.1.0" (instance $extension)))